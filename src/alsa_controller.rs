@@ -6,11 +6,94 @@ use std::io;
 use std::io::{Error, ErrorKind};
 use std::sync::mpsc::channel;
 
+/// Which mixer control the volume bar shows and changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlsaConfig {
+    pub card: String,    // Device name like "default" or "hw:1"
+    pub element: String, // Simple mixer element, e.g. "Master" or "PCM"
+    pub index: u32,
+    pub channel: mixer::SelemChannelId, // Channel the volume is read from, all are set
+}
+
+impl Default for AlsaConfig {
+    fn default() -> Self {
+        AlsaConfig {
+            card: "default".to_string(),
+            element: "Master".to_string(),
+            index: 0,
+            channel: mixer::SelemChannelId::Last,
+        }
+    }
+}
+
+impl AlsaConfig {
+    /// Takes a card number like "1" as "hw:1", other names are used as they are
+    pub fn card_device(card: &str) -> String {
+        if !card.is_empty() && card.chars().all(|c| c.is_ascii_digit()) {
+            format!("hw:{}", card)
+        } else {
+            card.to_string()
+        }
+    }
+
+    /// Parses an element like "PCM" or "PCM,1" into its name and index
+    pub fn parse_element(element: &str) -> Result<(String, u32), io::Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid mixer element {}", element),
+            )
+        };
+        let (name, index) = match element.rsplit_once(',') {
+            Some((name, index)) => (name, index.trim().parse().map_err(|_| invalid())?),
+            None => (element, 0),
+        };
+        if name.trim().is_empty() {
+            return Err(invalid());
+        }
+        Ok((name.trim().to_string(), index))
+    }
+
+    /// Parses a channel like "front-left" or "mono", "last" reads the last channel of the element
+    pub fn parse_channel(channel: &str) -> Result<mixer::SelemChannelId, io::Error> {
+        use mixer::SelemChannelId::*;
+        match channel.to_lowercase().replace('_', "-").as_str() {
+            "front-left" => Ok(FrontLeft),
+            "front-right" => Ok(FrontRight),
+            "rear-left" => Ok(RearLeft),
+            "rear-right" => Ok(RearRight),
+            "front-center" => Ok(FrontCenter),
+            "woofer" => Ok(Woofer),
+            "side-left" => Ok(SideLeft),
+            "side-right" => Ok(SideRight),
+            "rear-center" => Ok(RearCenter),
+            "mono" => Ok(mixer::SelemChannelId::mono()),
+            "last" => Ok(Last),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown mixer channel {}", channel),
+            )),
+        }
+    }
+
+    /// Name of the control events sent when the element's volume changes
+    pub fn event_name(&self) -> String {
+        format!("{} Playback Volume", self.element)
+    }
+
+    /// Name of the control events sent when the element is muted or unmuted
+    pub fn switch_event_name(&self) -> String {
+        format!("{} Playback Switch", self.element)
+    }
+}
+
 pub struct AlsaController {
+    config: AlsaConfig,
     current_volume_db_percentage: f32,
     current_volume_db: f32,
     volume_max_db: f32,
     broken: bool,
+    muted: bool, // Playback switch is off
     volume_min_db: f32,
     alsa_event_rx: std::sync::mpsc::Receiver<String>,
 }
@@ -27,23 +110,32 @@ fn get_normalized_volume(min: f32, max: f32, value: f32) -> f32 {
 }
 
 impl AlsaController {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new(config: AlsaConfig) -> Result<Self, io::Error> {
         // Hmm init we do
         // create struct
         // update volume
         debug!("AlsaController Init");
         let (alsa_event_tx, alsa_event_rx) = channel();
+        let card = config.card.clone();
         let mut sys_control = AlsaController {
+            config,
             current_volume_db_percentage: 0.0,
             current_volume_db: 0.0,
             volume_max_db: 0.0,
             volume_min_db: 0.0,
             alsa_event_rx: alsa_event_rx,
             broken: false,
+            muted: false,
         };
-        sys_control.update_volume().unwrap();
+        sys_control.update_volume()?;
         std::thread::spawn(move || {
-            let alsa_ctrl = Ctl::new("default", false).unwrap();
+            let alsa_ctrl = match Ctl::new(&card, false) {
+                Ok(value) => value,
+                Err(error) => {
+                    error!("Could not open alsa card {} for events {:?}", card, error);
+                    return;
+                }
+            };
             alsa_ctrl.subscribe_events(true).unwrap();
             loop {
                 match alsa_ctrl.read() {
@@ -82,18 +174,20 @@ impl AlsaController {
         //let (mixer_vol_min, mixer_vol_max) = mixer_channel.get_playback_volume_range(); // Returns weird and dumb alsa scaling. Dont use.
         self.volume_max_db = mixer_db_max.to_db();
         self.volume_min_db = mixer_db_min.to_db();
-        self.current_volume_db =
-            match mixer_channel.get_playback_vol_db(mixer::SelemChannelId::Last) {
-                Ok(value) => value.to_db(),
-                Err(error) => {
-                    if !self.broken {
-                        error!("Could not get playback volume! {}", error);
-                        self.broken = true;
-                    }
-                    self.volume_min_db
+        self.current_volume_db = match mixer_channel.get_playback_vol_db(self.config.channel) {
+            Ok(value) => value.to_db(),
+            Err(error) => {
+                if !self.broken {
+                    error!("Could not get playback volume! {}", error);
+                    self.broken = true;
                 }
-            };
+                self.volume_min_db
+            }
+        };
         self.current_volume_db_percentage = 1.0 - (self.current_volume_db / mixer_db_min.to_db());
+        // Elements without a switch can not be muted
+        self.muted = mixer_channel.has_playback_switch()
+            && mixer_channel.get_playback_switch(self.config.channel) == Ok(0);
         debug!(
             "Read current alsa volume as {}dB ({}%)",
             self.current_volume_db,
@@ -103,24 +197,36 @@ impl AlsaController {
 
     pub fn wait_for_volume_event(&mut self) -> bool {
         match self.alsa_event_rx.try_recv() {
-            Ok(event_string) => match event_string.as_str() {
-                "Master Playback Volume" => true,
-                _ => false,
-            },
+            Ok(event_string) => {
+                event_string == self.config.event_name()
+                    || event_string == self.config.switch_event_name()
+            }
             Err(_) => false,
         }
     }
 
+    fn open_mixer(&self) -> Result<mixer::Mixer, io::Error> {
+        mixer::Mixer::new(&self.config.card, true).map_err(|error| {
+            Error::new(
+                ErrorKind::Other,
+                format!("Failed to open alsa card {}: {}", self.config.card, error),
+            )
+        })
+    }
+
     pub fn update_volume(&mut self) -> Result<(), io::Error> {
         // Get handle to mixer channel
-        let mixer = mixer::Mixer::new("default", true).unwrap();
-        let mixer_select = mixer::SelemId::new("Master", 0);
+        let mixer = self.open_mixer()?;
+        let mixer_select = mixer::SelemId::new(&self.config.element, self.config.index);
         let mixer_channel = match mixer.find_selem(&mixer_select) {
             Some(value) => value,
             None => {
                 return Err(Error::new(
                     ErrorKind::Other,
-                    "Failed to open alsa interface",
+                    format!(
+                        "Failed to open alsa mixer element {},{} on {}",
+                        self.config.element, self.config.index, self.config.card
+                    ),
                 ));
             }
         };
@@ -139,14 +245,17 @@ impl AlsaController {
 
     pub fn volume_increment_db(&mut self, num_steps: u32) -> Result<(), io::Error> {
         // Get handle to mixer channel
-        let mixer = mixer::Mixer::new("default", true).unwrap();
-        let mixer_select = mixer::SelemId::new("Master", 0);
+        let mixer = self.open_mixer()?;
+        let mixer_select = mixer::SelemId::new(&self.config.element, self.config.index);
         let mixer_channel = match mixer.find_selem(&mixer_select) {
             Some(value) => value,
             None => {
                 return Err(Error::new(
                     ErrorKind::Other,
-                    "Failed to open alsa interface",
+                    format!(
+                        "Failed to open alsa mixer element {},{} on {}",
+                        self.config.element, self.config.index, self.config.card
+                    ),
                 ));
             }
         };
@@ -159,7 +268,7 @@ impl AlsaController {
             let to_db = mixer::MilliBel::from_db(to_db_part);
             mixer_channel
                 .set_playback_db_all(to_db, Round::Floor)
-                .unwrap();
+                .map_err(|error| Error::new(ErrorKind::Other, error))?;
             debug!(
                 "Increasing volume from {}dB to {}dB",
                 self.current_volume_db, to_db_part
@@ -170,15 +279,18 @@ impl AlsaController {
     }
     pub fn volume_decrement_db(&mut self, num_steps: u32) -> Result<(), io::Error> {
         // Get handle to mixer channel
-        self.update_volume().unwrap();
-        let mixer = mixer::Mixer::new("default", true).unwrap();
-        let mixer_select = mixer::SelemId::new("Master", 0);
+        self.update_volume()?;
+        let mixer = self.open_mixer()?;
+        let mixer_select = mixer::SelemId::new(&self.config.element, self.config.index);
         let mixer_channel = match mixer.find_selem(&mixer_select) {
             Some(value) => value,
             None => {
                 return Err(Error::new(
                     ErrorKind::Other,
-                    "Failed to open alsa interface",
+                    format!(
+                        "Failed to open alsa mixer element {},{} on {}",
+                        self.config.element, self.config.index, self.config.card
+                    ),
                 ));
             }
         };
@@ -195,11 +307,46 @@ impl AlsaController {
             );
             mixer_channel
                 .set_playback_db_all(to_db, Round::Floor)
-                .unwrap();
+                .map_err(|error| Error::new(ErrorKind::Other, error))?;
             self.update_volume_struct(&mixer_channel);
         }
         Ok(())
     }
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_mute(&mut self, muted: bool) -> Result<(), io::Error> {
+        let mixer = self.open_mixer()?;
+        let mixer_select = mixer::SelemId::new(&self.config.element, self.config.index);
+        let mixer_channel = match mixer.find_selem(&mixer_select) {
+            Some(value) if value.has_playback_switch() => value,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "Alsa mixer element {},{} can not be muted",
+                        self.config.element, self.config.index
+                    ),
+                ));
+            }
+        };
+        debug!("Setting alsa mute to {}", muted);
+        mixer_channel
+            .set_playback_switch_all(if muted { 0 } else { 1 })
+            .map_err(|error| Error::new(ErrorKind::Other, error))?;
+        self.update_volume_struct(&mixer_channel);
+        Ok(())
+    }
+
+    /// Mutes or unmutes the element, returns true if it is muted now
+    pub fn toggle_mute(&mut self) -> Result<bool, io::Error> {
+        self.update_volume()?;
+        let muted = !self.muted;
+        self.set_mute(muted)?;
+        Ok(muted)
+    }
+
     pub fn get_human_ear_volume_normalized(&mut self) -> f64 {
         let volume = get_normalized_volume(
             self.volume_min_db,
@@ -214,3 +361,33 @@ impl AlsaController {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alsa_config_parsing() {
+        assert_eq!(AlsaConfig::card_device("1"), "hw:1");
+        assert_eq!(AlsaConfig::card_device("default"), "default");
+        assert_eq!(
+            AlsaConfig::parse_element("PCM").unwrap(),
+            ("PCM".to_string(), 0)
+        );
+        assert_eq!(
+            AlsaConfig::parse_element("Headphone,1").unwrap(),
+            ("Headphone".to_string(), 1)
+        );
+        assert!(AlsaConfig::parse_element("PCM,x").is_err());
+        assert_eq!(
+            AlsaConfig::parse_channel("Front_Left").unwrap(),
+            mixer::SelemChannelId::FrontLeft
+        );
+        assert!(AlsaConfig::parse_channel("middle").is_err());
+        let config = AlsaConfig {
+            element: "PCM".to_string(),
+            ..AlsaConfig::default()
+        };
+        assert_eq!(config.event_name(), "PCM Playback Volume");
+    }
+}
@@ -1,9 +1,27 @@
+use crate::daemon_cache::{cache_key_for_url, DownloadCache, SharedCache};
+use crate::daemon_downloader::{
+    directory_size, DownloadPool, DownloadSettings, Downloader, RetryPolicy,
+    DEFAULT_DOWNLOAD_WORKERS,
+};
+use crate::daemon_queue::{DaemonQueue, InputObject, ObjectTypes};
 use crate::external_program_status::*;
+use crate::media_resolver::{AutoResolver, MediaResolver};
+use crate::socket_com::{
+    decode_message, ControlCommand, FormatOptions, PlaylistOptions, Request, SOCKET_PATH,
+};
+use crate::status_watcher::{PlaybackState, RepeatMode, QUEUE_FILE_PATH, STATUS_FILE_PATH};
+use chrono::Local;
 use mpv;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixListener;
 use std::path;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // Functionality
 /*
@@ -29,25 +47,123 @@ enum MPVCommand {
     Playfile,
     Seekfile,
     CycleSubtitles,
-    Speed,
-    DisableAudioPitchCorrection,
-    EnableAudioPitchCorrection,
     Play,
     Pause,
+    Stop,
+    YtdlFormat, // Format selector for streamed urls, empty for mpv's default
+    Mute,       // "yes" or "no"
+    Volume,     // Percent
 }
 
 enum MPVFeedbackType {
     Idle,
+    LoadFailed, // The file could not be played, the message has the error
+    Shutdown,   // The mpv window was closed, the daemon exits
 }
 
+/// Daemon settings given on the command line
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    pub download_workers: usize,
+    pub download_directory: String,
+    pub prefetch_count: usize, // Upcoming entries downloaded ahead, 0 streams everything
+    pub download_budget: u64,  // Bytes the download directory may use for prefetching
+    pub cache_size: u64,       // Bytes of played downloads kept for queueing them again
+    pub format: FormatOptions, // Used for entries queued without format options
+    pub retry: RetryPolicy,
+    pub resolver: Arc<dyn MediaResolver>, // Resolves playlists and downloads prefetched entries
+    pub external_players: Vec<PlayerConfig>, // Paused while we play
+    pub ducking: Option<Ducking>,         // Lower their volume instead of pausing them
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            download_workers: DEFAULT_DOWNLOAD_WORKERS,
+            download_directory: "/tmp/smqueue-downloads".to_string(),
+            prefetch_count: 2,
+            download_budget: 2 * 1024 * 1024 * 1024,
+            cache_size: 1024 * 1024 * 1024,
+            format: FormatOptions::default(),
+            retry: RetryPolicy::default(),
+            resolver: Arc::new(AutoResolver::default()),
+            external_players: PlayerConfig::defaults(),
+            ducking: None,
+        }
+    }
+}
+
+/// Highest volume mpv allows without raising its volume-max option
+const MAX_MPV_VOLUME: u64 = 130;
+
+/// How often the status file is refreshed while downloads report progress
+const DOWNLOAD_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct Daemon {
     mpv_controller: Sender<MPVMessage>,
     mpv_feedback: Receiver<MPVFeedback>,
+    queue: DaemonQueue,
+    playback_state: PlaybackState,
+    repeat_mode: RepeatMode,
+    shuffle: bool,
+    stop_idles: u32, // Idle events mpv still sends for stops we asked for
+    volume: u64,     // mpv's volume in percent
+    muted: bool,
+    downloads: HashMap<u64, Downloader>, // Keyed by queue id
+    download_pool: DownloadPool,
+    cache: SharedCache,
+    config: DaemonConfig,
+}
+
+// Resolves a lazily queued playlist into "<id or url> - <title>" entries
+fn expand_playlist(
+    resolver: &dyn MediaResolver,
+    url: &str,
+    options: &PlaylistOptions,
+) -> Vec<String> {
+    let videos = match resolver.resolve(url, true) {
+        Ok(resolution) => resolution.entries,
+        Err(error) => {
+            log::warn!("Could not expand playlist {} {:?}", url, error);
+            vec![]
+        }
+    };
+    options
+        .select(videos)
+        .iter()
+        .filter_map(|video| video.entry_string())
+        .collect()
+}
+
+// Hands every decoded socket message over to the daemon loop
+fn listen_for_requests(listener: UnixListener, request_tx: Sender<Request>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(value) => value,
+            Err(error) => {
+                log::warn!("Socket connection failed {:?}", error);
+                continue;
+            }
+        };
+        let mut raw = vec![];
+        if let Err(error) = stream.read_to_end(&mut raw) {
+            log::warn!("Could not read socket message {:?}", error);
+            continue;
+        }
+        match decode_message(&raw) {
+            Ok(request) => {
+                if request_tx.send(request).is_err() {
+                    return;
+                }
+            }
+            Err(error) => log::warn!("Dropping invalid message {:?}", error),
+        }
+    }
 }
 
 impl Daemon {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new(config: DaemonConfig) -> Result<Self, io::Error> {
         /*  TODO
             MPV backend object init
             socket connection for reciving commands
@@ -58,6 +174,8 @@ impl Daemon {
             channel();
         let (mpv_feedback_tx, mpv_feedback_rx): (Sender<MPVFeedback>, Receiver<MPVFeedback>) =
             channel();
+        let external_players = config.external_players.clone();
+        let ducking = config.ducking;
         let mpv_thread = std::thread::spawn(move || {
             let mut mpv_builder =
                 mpv::MpvHandlerBuilder::new().expect("Failed to init MPV builder");
@@ -67,8 +185,10 @@ impl Daemon {
             mpv_builder.set_option("osc", true).unwrap();
             let mut mpv = mpv_builder.build().expect("Failed to build MPV handler");
             mpv.set_option("idle", "yes").unwrap();
-            let mut spotify_was_playing = false;
-            let mut mpd_was_playing = false;
+            // Players paused or ducked for our playback, restored once mpv is idle again
+            let mut external_players = PlayerRegistry::from_config(&external_players)
+                .with_ducking(ducking)
+                .with_state_file(path::Path::new(PAUSED_PLAYERS_FILE_PATH));
 
             'main: loop {
                 while let Some(event) = mpv.wait_event(0.0) {
@@ -81,19 +201,20 @@ impl Daemon {
                         // while Idle will be triggered when the queue will end
                         mpv::Event::Shutdown => {
                             println!("MPV shutting down!");
+                            let _ = mpv_feedback_tx.send(MPVFeedback {
+                                feedbacktype: MPVFeedbackType::Shutdown,
+                                message: "".to_string(),
+                            });
+                            external_players.finish_ramps();
                             break 'main;
                         }
                         mpv::Event::Idle => {
-                            println!("Spotify {}", spotify_was_playing);
-                            println!("mpd {}", mpd_was_playing);
-                            if spotify_was_playing {
-                                spotify_was_playing = false;
-                                spotify_play_pause();
-                            }
-                            if mpd_was_playing {
-                                mpd_was_playing = false;
-                                mpd_play();
-                            }
+                            println!(
+                                "Paused players {:?}, ducked players {:?}",
+                                external_players.paused_names(),
+                                external_players.ducked_names()
+                            );
+                            external_players.resume_paused();
                             let message = MPVFeedback {
                                 feedbacktype: MPVFeedbackType::Idle,
                                 message: "".to_string(),
@@ -101,20 +222,20 @@ impl Daemon {
                             mpv_feedback_tx.send(message).unwrap();
                         }
                         mpv::Event::Unpause | mpv::Event::StartFile => {
-                            if spotify_playing() {
-                                spotify_was_playing = true;
-                                spotify_play_pause();
-                            }
-                            if mpd_playing() {
-                                mpd_was_playing = true;
-                                mpd_pause();
-                            }
+                            external_players.pause_playing();
+                        }
+                        mpv::Event::EndFile(Err(error)) => {
+                            let _ = mpv_feedback_tx.send(MPVFeedback {
+                                feedbacktype: MPVFeedbackType::LoadFailed,
+                                message: format!("{:?}", error),
+                            });
                         }
                         _ => {
                             println!("{:?} Got event", event);
                         }
                     };
                 }
+                external_players.tick();
                 if let Ok(recv) = mpv_instance_rx.try_recv() {
                     match recv.command {
                         MPVCommand::Pause => mpv.set_property("pause", true).unwrap(),
@@ -127,15 +248,24 @@ impl Daemon {
                             ])
                             .expect("Error loading file");
                         }
-                        MPVCommand::Speed => {
-                            let speed = recv.message.parse::<f64>().unwrap();
-                            mpv.set_property("speed", speed).unwrap();
+                        MPVCommand::Stop => mpv.command(&["stop"]).unwrap(),
+                        MPVCommand::Volume => {
+                            let volume = recv.message.parse::<f64>().unwrap();
+                            if let Err(error) = mpv.set_property("volume", volume) {
+                                log::warn!("Could not set volume {:?}", error);
+                            }
                         }
-                        MPVCommand::DisableAudioPitchCorrection => {
-                            mpv.set_property("audio-pitch-correction", "no").unwrap()
+                        MPVCommand::Mute => {
+                            if let Err(error) = mpv.set_property("mute", &recv.message as &str) {
+                                log::warn!("Could not set mute {:?}", error);
+                            }
                         }
-                        MPVCommand::EnableAudioPitchCorrection => {
-                            mpv.set_property("audio-pitch-correction", "yes").unwrap()
+                        MPVCommand::YtdlFormat => {
+                            if let Err(error) =
+                                mpv.set_property("ytdl-format", &recv.message as &str)
+                            {
+                                log::warn!("Could not set ytdl-format {:?}", error);
+                            }
                         }
                         _ => (),
                     }
@@ -145,44 +275,466 @@ impl Daemon {
         let com = Daemon {
             mpv_controller: mpv_instance_tx,
             mpv_feedback: mpv_feedback_rx,
+            queue: DaemonQueue::new()?,
+            playback_state: PlaybackState::Idle,
+            repeat_mode: RepeatMode::Off,
+            downloads: HashMap::new(),
+            download_pool: DownloadPool::new(config.download_workers),
+            cache: DownloadCache::open(
+                path::Path::new(&config.download_directory),
+                config.cache_size,
+            )?
+            .shared(),
+            config,
+            shuffle: false,
+            stop_idles: 0,
+            volume: 100,
+            muted: false,
         };
         Ok(com)
     }
 
-    pub fn mpv_play_file(&self, file: &str) -> () {
-        self.mpv_controller
-            .send(MPVMessage {
-                command: MPVCommand::Playfile,
-                message: file.to_string(),
-            })
-            .unwrap();
+    /// Serves requests from the unix socket and plays the queue until mpv shuts down
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        let _ = fs::remove_file(SOCKET_PATH);
+        let listener = UnixListener::bind(SOCKET_PATH)?;
+        let (request_tx, request_rx) = channel();
+        std::thread::spawn(move || listen_for_requests(listener, request_tx));
+        let result = self.serve(request_rx);
+        // Running downloads are cancelled and the workers stopped, however the loop ended
+        for (_, download) in self.downloads.drain() {
+            download.cancel();
+        }
+        self.download_pool.shutdown();
+        result
     }
 
-    pub fn mpv_disable_audio_pitch_correction(&self) -> () {
-        self.mpv_controller
-            .send(MPVMessage {
-                command: MPVCommand::DisableAudioPitchCorrection,
-                message: "".to_string(),
-            })
-            .unwrap();
+    fn serve(&mut self, request_rx: Receiver<Request>) -> Result<(), io::Error> {
+        self.write_status()?;
+        let mut last_download_status = Instant::now();
+
+        loop {
+            let mut changed = false;
+            while let Ok(request) = request_rx.try_recv() {
+                log::debug!("Handling request {:?}", request);
+                self.handle_request(request);
+                changed = true;
+            }
+            while let Ok(feedback) = self.mpv_feedback.try_recv() {
+                match feedback.feedbacktype {
+                    MPVFeedbackType::Idle => {
+                        // Idle after playing means the current entry finished,
+                        // unless it comes from an explicit stop
+                        if self.stop_idles > 0 {
+                            self.stop_idles -= 1;
+                        } else if let PlaybackState::Playing = self.playback_state {
+                            match self.repeat_mode {
+                                RepeatMode::One => self.play_current(),
+                                _ => self.advance(),
+                            }
+                            changed = true;
+                        }
+                    }
+                    MPVFeedbackType::LoadFailed => {
+                        log::warn!("mpv could not play the current entry: {}", feedback.message);
+                        // Repeating it would fail forever, the Idle that follows moves on
+                        if let RepeatMode::One = self.repeat_mode {
+                            log::warn!("Leaving repeat one");
+                            self.repeat_mode = RepeatMode::Off;
+                            changed = true;
+                        }
+                    }
+                    MPVFeedbackType::Shutdown => return Ok(()),
+                }
+            }
+            // Scheduled entries are released here, the loop tick acts as the timer
+            if self.queue.release_scheduled(Local::now().timestamp()) {
+                self.play_current();
+            }
+            self.queue.process_prequeue();
+            if self.prefetch() || self.poll_downloads() {
+                changed = true;
+            }
+            if !self.downloads.is_empty()
+                && last_download_status.elapsed() >= DOWNLOAD_STATUS_INTERVAL
+            {
+                last_download_status = Instant::now();
+                changed = true;
+            }
+            // An explicit stop keeps the queue waiting until playback is started again
+            if let PlaybackState::Idle = self.playback_state {
+                if self.queue.current_id().is_some() {
+                    if self.shuffle {
+                        self.queue.shuffle_next();
+                    }
+                    self.play_current();
+                    changed = true;
+                }
+            }
+            if changed {
+                self.write_status()?;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
     }
-    pub fn mpv_enable_audio_pitch_correction(&self) -> () {
-        self.mpv_controller
-            .send(MPVMessage {
-                command: MPVCommand::EnableAudioPitchCorrection,
-                message: "".to_string(),
-            })
-            .unwrap();
+
+    fn handle_request(&mut self, request: Request) {
+        match request {
+            Request::QueueEntry {
+                priority,
+                entry_type,
+                location,
+                schedule,
+                playlist,
+                group,
+                format,
+            } => {
+                let object_type = match ObjectTypes::from_entry_type(&entry_type) {
+                    Some(value) => value,
+                    None => {
+                        log::warn!("Can not queue entry type {:?}", entry_type);
+                        return;
+                    }
+                };
+                let input = match playlist {
+                    Some(options) => InputObject::new_playlist(location, priority, options),
+                    None => InputObject::new(location, priority, object_type),
+                }
+                .with_group(group)
+                .with_format(format);
+                match schedule {
+                    Some(value) => {
+                        self.queue.add_scheduled(input, value);
+                    }
+                    None => {
+                        self.queue.add_to_queue(input);
+                    }
+                }
+            }
+            Request::Control {
+                command,
+                argument,
+                priority,
+            } => match command {
+                ControlCommand::ClearQueue => {
+                    self.queue.clear();
+                    self.cancel_removed_downloads();
+                }
+                ControlCommand::StopPlayback => {
+                    if let PlaybackState::Playing | PlaybackState::Paused = self.playback_state {
+                        self.stop_idles += 1;
+                    }
+                    self.playback_state = PlaybackState::Stopped;
+                    self.mpv_send(MPVCommand::Stop);
+                }
+                ControlCommand::PausePlayback => {
+                    if let PlaybackState::Playing = self.playback_state {
+                        self.playback_state = PlaybackState::Paused;
+                        self.mpv_send(MPVCommand::Pause);
+                    }
+                }
+                ControlCommand::StartPlayback => match self.playback_state {
+                    PlaybackState::Paused => {
+                        self.playback_state = PlaybackState::Playing;
+                        self.mpv_send(MPVCommand::Play);
+                    }
+                    _ => self.play_current(),
+                },
+                ControlCommand::SkipAndPlay => self.advance(),
+                ControlCommand::RemoveFromQueue => {
+                    if let Some(id) = argument {
+                        let removing_current = self.queue.current_id() == Some(id);
+                        if self.queue.remove(id) {
+                            self.cancel_removed_downloads();
+                            if removing_current {
+                                if self.shuffle {
+                                    self.queue.shuffle_next();
+                                }
+                                self.play_current();
+                            }
+                        }
+                    }
+                }
+                ControlCommand::PromoteEntry => {
+                    if let Some(id) = argument {
+                        if self.queue.promote(id) {
+                            self.play_current();
+                        }
+                    }
+                }
+                ControlCommand::SetRepeatMode => match argument.and_then(RepeatMode::from_u64) {
+                    Some(mode) => self.repeat_mode = mode,
+                    None => log::warn!("Invalid repeat mode {:?}", argument),
+                },
+                ControlCommand::SetShuffle => self.shuffle = argument.unwrap_or(0) != 0,
+                ControlCommand::SetMute => {
+                    self.muted = argument.unwrap_or(0) != 0;
+                    self.mpv_controller
+                        .send(MPVMessage {
+                            command: MPVCommand::Mute,
+                            message: if self.muted { "yes" } else { "no" }.to_string(),
+                        })
+                        .unwrap();
+                }
+                ControlCommand::SetVolume => {
+                    self.volume = argument.unwrap_or(self.volume).min(MAX_MPV_VOLUME);
+                    self.mpv_controller
+                        .send(MPVMessage {
+                            command: MPVCommand::Volume,
+                            message: self.volume.to_string(),
+                        })
+                        .unwrap();
+                }
+                ControlCommand::RemoveGroup | ControlCommand::PromoteGroup => {
+                    if let Some(group) = argument {
+                        let current_id = self.queue.current_id();
+                        let changed = match command {
+                            ControlCommand::RemoveGroup => self.queue.remove_group(group),
+                            _ => self.queue.promote_group(group),
+                        };
+                        self.cancel_removed_downloads();
+                        if changed && self.queue.current_id() != current_id {
+                            self.play_current();
+                        }
+                    }
+                }
+                ControlCommand::SetGroupPriority => {
+                    if let Some(group) = argument {
+                        self.queue.set_group_priority(group, priority);
+                    }
+                }
+                ControlCommand::Undo => {
+                    let current_id = self.queue.current_id();
+                    if self.queue.undo() && self.queue.current_id() != current_id {
+                        self.play_current();
+                    }
+                }
+            },
+        }
+    }
+
+    // Starts downloading the next entries while there is room in the download budget.
+    // Returns true when a download was started.
+    fn prefetch(&mut self) -> bool {
+        if self.config.prefetch_count == 0 {
+            return false;
+        }
+        let count = self.config.prefetch_count;
+        let upcoming: Vec<_> = self
+            .queue
+            .upcoming_videos(count)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (id, _, _))| !self.downloads.contains_key(id))
+            .collect();
+        if upcoming.is_empty() {
+            return false;
+        }
+        let directory = path::Path::new(&self.config.download_directory);
+        if let Err(error) = fs::create_dir_all(directory) {
+            log::warn!(
+                "Could not create download directory {:?}: {}",
+                directory,
+                error
+            );
+            return false;
+        }
+        // The cache shares the directory, files kept only for queueing them again
+        // are limited by the cache size and do not count against the budget
+        let queued: Vec<String> = self
+            .downloads
+            .values_mut()
+            .filter_map(|download| download.local_path())
+            .collect();
+        let cached = self.cache.lock().unwrap().size_without(&queued);
+        let mut started = false;
+        for (position, (id, url, format)) in upcoming {
+            // Checked per entry, downloads started before may have filled the budget meanwhile
+            let used = directory_size(directory).saturating_sub(cached);
+            if used >= self.config.download_budget {
+                log::debug!("Download budget used up, streaming upcoming entries");
+                break;
+            }
+            // Entries closer to the front are downloaded first
+            let priority = (count - position) as u64;
+            let settings = DownloadSettings {
+                directory: self.config.download_directory.clone(),
+                cache: Some(self.cache.clone()),
+                format: format.unwrap_or_default().or(&self.config.format),
+                retry: self.config.retry,
+                resolver: self.config.resolver.clone(),
+            };
+            match Downloader::new(url.clone(), &self.download_pool, priority, &settings) {
+                Ok(download) => {
+                    log::info!("Prefetching {}", url);
+                    self.downloads.insert(id, download);
+                    started = true;
+                }
+                Err(error) => log::warn!("Could not prefetch {}: {}", url, error),
+            }
+        }
+        started
     }
-    pub fn mpv_set_speed(&self, speed: f64) -> () {
+
+    // Picks up finished downloads, failed ones are reported here and streamed when played.
+    // Returns true when a download finished.
+    fn poll_downloads(&mut self) -> bool {
+        let mut finished = false;
+        for (id, download) in self.downloads.iter_mut() {
+            let downloaded = match download.check_download_ready() {
+                Some(value) => value,
+                None => continue,
+            };
+            finished = true;
+            for video in downloaded {
+                log::info!(
+                    "Downloaded {} for queue entry {} ({}, {})",
+                    video.name(),
+                    id,
+                    video.container().unwrap_or("unknown container"),
+                    video
+                        .duration()
+                        .map_or("unknown duration".to_string(), |value| format!(
+                            "{:.0}s",
+                            value
+                        ))
+                );
+            }
+            if !download.has_failed() {
+                continue;
+            }
+            for failure in download.failures() {
+                log::error!(
+                    "Download of queue entry {} ({}) failed after {} attempts: {}\n{}",
+                    id,
+                    failure.url,
+                    failure.attempts,
+                    failure.error,
+                    failure.stderr
+                );
+            }
+        }
+        finished
+    }
+
+    // Stops downloads of entries that are no longer in the queue.
+    // Their finished files stay in the cache until it is over its size limit,
+    // files the cache does not keep are removed right away.
+    fn cancel_removed_downloads(&mut self) {
+        let removed: Vec<u64> = self
+            .downloads
+            .keys()
+            .filter(|id| !self.queue.contains(**id))
+            .cloned()
+            .collect();
+        for id in removed {
+            if let Some(download) = self.downloads.remove(&id) {
+                download.cancel();
+                download.cleanup_downloaded_videoes();
+            }
+        }
+        // Files of queued entries are kept, they are about to be played
+        let keep: Vec<String> = self
+            .downloads
+            .values_mut()
+            .filter_map(|download| download.local_path())
+            .collect();
+        self.cache.lock().unwrap().evict(&keep);
+    }
+
+    // Moves past the current entry according to the playback modes and plays the next one
+    fn advance(&mut self) {
+        match self.repeat_mode {
+            RepeatMode::Queue => self.queue.requeue_current(),
+            _ => self.queue.pop_current(),
+        }
+        // Files of entries that are done playing are removed here
+        self.cancel_removed_downloads();
+        if self.shuffle {
+            self.queue.shuffle_next();
+        }
+        self.play_current();
+    }
+
+    // Starts the entry at the front of the queue, or stops mpv if there is none
+    fn play_current(&mut self) {
+        while let Some((url, options)) = self.queue.current_playlist() {
+            let videos = expand_playlist(self.config.resolver.as_ref(), &url, &options);
+            log::info!("Expanded playlist {} into {} entries", url, videos.len());
+            self.queue.expand_current(videos);
+        }
+        let downloaded = self
+            .queue
+            .current_id()
+            .and_then(|id| self.downloads.get_mut(&id))
+            .and_then(|download| download.local_path());
+        let format = self
+            .queue
+            .current_format()
+            .unwrap_or_default()
+            .or(&self.config.format);
+        // Videos played before may still be cached even if they were not prefetched this time
+        let cached = self
+            .queue
+            .current_path()
+            .and_then(|url| cache_key_for_url(&url))
+            .and_then(|key| {
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .lookup(&(key + &format.cache_suffix()))
+            });
+        match downloaded.or(cached).or_else(|| self.queue.current_path()) {
+            Some(path) => {
+                let now = Local::now().timestamp();
+                self.cache.lock().unwrap().mark_played(&path, now);
+                // Only matters when mpv streams the url itself
+                self.mpv_controller
+                    .send(MPVMessage {
+                        command: MPVCommand::YtdlFormat,
+                        message: format.selector().unwrap_or_default(),
+                    })
+                    .unwrap();
+                self.playback_state = PlaybackState::Playing;
+                self.mpv_play_file(&path);
+            }
+            None => {
+                self.playback_state = PlaybackState::Idle;
+                self.mpv_send(MPVCommand::Stop);
+            }
+        }
+    }
+
+    // Download progress is appended to the status file as "download;<id>;<progress>" lines,
+    // mpv's volume as a "volume;<percent>;<muted>" line
+    fn write_status(&mut self) -> Result<(), io::Error> {
+        self.queue
+            .write_queue_file(path::Path::new(QUEUE_FILE_PATH))?;
+        // #TODO: Report actual playback position from mpv
+        let mut file = fs::File::create(STATUS_FILE_PATH)?;
+        let shuffle = if self.shuffle { "Shuffle" } else { "Ordered" };
+        let mut contents = format!(
+            "{}\n{}\n{}\n{}\n",
+            0.0, self.playback_state, self.repeat_mode, shuffle
+        );
+        contents += &format!("volume;{};{}\n", self.volume, self.muted as u8);
+        for (id, download) in self.downloads.iter() {
+            contents += &format!("download;{};{}\n", id, download.describe_progress());
+        }
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    fn mpv_send(&self, command: MPVCommand) {
         self.mpv_controller
             .send(MPVMessage {
-                command: MPVCommand::Speed,
-                message: speed.to_string(),
+                command,
+                message: "".to_string(),
             })
             .unwrap();
     }
-    pub fn mpv_play_add(&self, file: &str) -> () {
+
+    pub fn mpv_play_file(&self, file: &str) -> () {
         self.mpv_controller
             .send(MPVMessage {
                 command: MPVCommand::Playfile,
@@ -191,3 +743,37 @@ impl Daemon {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_resolver::{Resolution, ScriptedResolver};
+
+    #[test]
+    fn test_expand_playlist() {
+        let url = "https://www.youtube.com/playlist?list=demo";
+        let resolver = ScriptedResolver::default().resolves(
+            url,
+            Resolution {
+                title: Some("Demo".to_string()),
+                playlist: true,
+                entries: vec![
+                    ScriptedResolver::video("a", "First"),
+                    ScriptedResolver::video("b", "Second"),
+                    ScriptedResolver::video("c", "Third"),
+                ],
+            },
+        );
+        let options = PlaylistOptions {
+            reverse: true,
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            expand_playlist(&resolver, url, &options),
+            vec!["c - Third".to_string(), "b - Second".to_string()]
+        );
+        // Playlists that can not be resolved expand to nothing
+        assert!(expand_playlist(&resolver, "https://example.com", &options).is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+
+use crate::media_resolver::{is_direct_media_url, url_id};
+
+/// Name of the index file kept next to the cached downloads
+pub const CACHE_INDEX_FILE: &str = "cache.index";
+
+pub type SharedCache = Arc<Mutex<DownloadCache>>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct CacheEntry {
+    key: String, // "<extractor>-<video id>"
+    size: u64,
+    last_played: i64, // Unix time, set to the download time until the entry is played
+    path: String,
+}
+
+/// Downloads keyed by extractor and video id, so queueing a video again reuses the file.
+/// The index is stored as "key;size;last played;path" lines in the download directory.
+#[derive(Debug)]
+pub struct DownloadCache {
+    index_path: path::PathBuf,
+    size_limit: u64,
+    entries: Vec<CacheEntry>,
+}
+
+/// Cache key of a video, the extractor is lowercased so "Youtube" and "youtube" match
+pub fn cache_key(extractor: &str, id: &str) -> String {
+    format!("{}-{}", extractor.to_lowercase(), id)
+}
+
+/// Cache key of a YouTube watch url or direct media link, used before the url has been resolved
+pub fn cache_key_for_url(url: &str) -> Option<String> {
+    if is_direct_media_url(url) {
+        return Some(cache_key("http", &url_id(url)));
+    }
+    let id = url
+        .strip_prefix("https://www.youtube.com/watch?v=")
+        .or_else(|| url.strip_prefix("https://youtu.be/"))?;
+    let id = id.split('&').next()?;
+    if id.is_empty() {
+        return None;
+    }
+    Some(cache_key("youtube", id))
+}
+
+impl DownloadCache {
+    /// Loads the index in the directory, entries whose file is gone are dropped
+    pub fn open(directory: &path::Path, size_limit: u64) -> Result<Self, io::Error> {
+        fs::create_dir_all(directory)?;
+        let index_path = directory.join(CACHE_INDEX_FILE);
+        let mut entries = vec![];
+        if let Ok(contents) = fs::read_to_string(&index_path) {
+            for line in contents.lines() {
+                let fields: Vec<&str> = line.splitn(4, ';').collect();
+                if fields.len() != 4 {
+                    warn!("Skipping invalid cache index line {:?}", line);
+                    continue;
+                }
+                let (size, last_played) = match (fields[1].parse(), fields[2].parse()) {
+                    (Ok(size), Ok(last_played)) => (size, last_played),
+                    _ => {
+                        warn!("Skipping invalid cache index line {:?}", line);
+                        continue;
+                    }
+                };
+                if path::Path::new(fields[3]).exists() {
+                    entries.push(CacheEntry {
+                        key: fields[0].to_string(),
+                        size,
+                        last_played,
+                        path: fields[3].to_string(),
+                    });
+                }
+            }
+        }
+        Ok(DownloadCache {
+            index_path,
+            size_limit,
+            entries,
+        })
+    }
+
+    pub fn shared(self) -> SharedCache {
+        Arc::new(Mutex::new(self))
+    }
+
+    fn save(&self) {
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents += &format!(
+                "{};{};{};{}\n",
+                entry.key, entry.size, entry.last_played, entry.path
+            );
+        }
+        if let Err(error) = fs::write(&self.index_path, contents) {
+            warn!(
+                "Could not write cache index {:?}: {}",
+                self.index_path, error
+            );
+        }
+    }
+
+    /// Path of the cached file for the key, if it is still on disk
+    pub fn lookup(&mut self, key: &str) -> Option<String> {
+        let index = self.entries.iter().position(|entry| entry.key == key)?;
+        if path::Path::new(&self.entries[index].path).exists() {
+            return Some(self.entries[index].path.clone());
+        }
+        debug!("Cached file for {} is gone", key);
+        self.entries.remove(index);
+        self.save();
+        None
+    }
+
+    pub fn insert(&mut self, key: &str, file_path: &str, now: i64) {
+        let size = fs::metadata(file_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        self.entries.retain(|entry| entry.key != key);
+        self.entries.push(CacheEntry {
+            key: key.to_string(),
+            size,
+            last_played: now,
+            path: file_path.to_string(),
+        });
+        self.save();
+    }
+
+    pub fn contains(&self, file_path: &str) -> bool {
+        self.entries.iter().any(|entry| entry.path == file_path)
+    }
+
+    pub fn mark_played(&mut self, file_path: &str, now: i64) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.path == file_path)
+        {
+            entry.last_played = now;
+            self.save();
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Size of the cached files other than the excluded ones, e.g. downloads still queued
+    pub fn size_without(&self, excluded: &[String]) -> u64 {
+        self.entries
+            .iter()
+            .filter(|entry| !excluded.contains(&entry.path))
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    /// Removes least recently played files until the cache fits its size limit.
+    /// Files in keep, e.g. the one playing, are never removed.
+    pub fn evict(&mut self, keep: &[String]) -> Vec<String> {
+        let mut removed = vec![];
+        while self.size() > self.size_limit {
+            let oldest = self
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| !keep.contains(&entry.path))
+                .min_by_key(|(_, entry)| entry.last_played)
+                .map(|(index, _)| index);
+            let entry = match oldest {
+                Some(index) => self.entries.remove(index),
+                None => break,
+            };
+            debug!("Evicting {} from the download cache", entry.key);
+            if let Err(error) = fs::remove_file(&entry.path) {
+                warn!("Could not remove {}: {}", entry.path, error);
+            }
+            removed.push(entry.path);
+        }
+        if !removed.is_empty() {
+            self.save();
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_and_evict_least_recently_played() {
+        let directory =
+            std::env::temp_dir().join(format!("smqueue-cache-{}", uuid::Uuid::new_v4()));
+        let mut cache = DownloadCache::open(&directory, 10).unwrap();
+        let mut paths = vec![];
+        for (index, name) in ["a", "b", "c"].iter().enumerate() {
+            let file_path = directory.join(name.to_string() + ".mkv");
+            fs::write(&file_path, b"12345").unwrap();
+            let file_path = file_path.to_str().unwrap().to_string();
+            cache.insert(&cache_key("Youtube", name), &file_path, index as i64);
+            paths.push(file_path);
+        }
+        assert_eq!(cache.lookup("youtube-a"), Some(paths[0].clone()));
+        assert_eq!(cache.lookup("youtube-d"), None);
+        assert!(cache.contains(&paths[1]));
+
+        // "a" was played last, "b" is playing, so "c" is the one to go
+        cache.mark_played(&paths[0], 10);
+        assert_eq!(cache.evict(&[paths[1].clone()]), vec![paths[2].clone()]);
+        assert!(!path::Path::new(&paths[2]).exists());
+
+        // The index survives a restart
+        let mut reopened = DownloadCache::open(&directory, 10).unwrap();
+        assert_eq!(reopened.lookup("youtube-a"), Some(paths[0].clone()));
+        assert_eq!(reopened.lookup("youtube-c"), None);
+        assert_eq!(reopened.size(), 10);
+        assert_eq!(reopened.size_without(&[paths[0].clone()]), 5);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key_for_url() {
+        assert_eq!(
+            cache_key_for_url("https://www.youtube.com/watch?v=138ajKRMzIY&t=10"),
+            Some("youtube-138ajKRMzIY".to_string())
+        );
+        assert_eq!(
+            cache_key_for_url("https://example.com/video.mp4"),
+            Some(cache_key("http", &url_id("https://example.com/video.mp4")))
+        );
+        assert_eq!(cache_key_for_url("https://example.com/video"), None);
+    }
+}
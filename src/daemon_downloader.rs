@@ -1,12 +1,21 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::io::{Read, Write};
 use std::path;
-use std::process::Command;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+use crate::daemon_cache::SharedCache;
+use crate::media_resolver::{
+    url_extension, DownloadError, DownloadTarget, FinalFile, MediaResolver,
+};
+use crate::socket_com::FormatOptions;
 
 use log::{debug, warn};
 
@@ -14,141 +23,804 @@ use log::{debug, warn};
 pub struct Downloader {
     finished: bool,
     failed: bool,
-    url: String,
     download_directory: String,
-    finished_notifier: Receiver<Vec<DownloadedObject>>,
+    cache: Option<SharedCache>,
+    finished_notifier: Receiver<DownloadResults>,
     downloaded_objects: Vec<DownloadedObject>,
+    failures: Vec<DownloadFailure>,
+    progress: Arc<Mutex<HashMap<String, DownloadProgress>>>, // Keyed by video url
+    cancel_handle: CancelHandle,
+}
+
+/// Total size of the files directly inside a directory
+pub fn directory_size(directory: &path::Path) -> u64 {
+    match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+/// How many videos are downloaded at the same time unless configured otherwise
+pub const DEFAULT_DOWNLOAD_WORKERS: usize = 2;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct PendingJob {
+    priority: u64,
+    sequence: u64, // Keeps jobs with the same priority in submission order
+    job: Job,
+}
+
+#[derive(Default)]
+struct PoolState {
+    pending: Vec<PendingJob>,
+    next_sequence: u64,
+    shutdown: bool,
+}
+
+/// Fixed number of worker threads shared by all downloads.
+/// Waiting jobs run highest priority first, so entries about to play are fetched first.
+#[derive(Clone)]
+pub struct DownloadPool {
+    state: Arc<(Mutex<PoolState>, Condvar)>,
+}
+
+impl std::fmt::Debug for DownloadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.state.0.lock().unwrap();
+        f.debug_struct("DownloadPool")
+            .field("pending", &state.pending.len())
+            .finish()
+    }
+}
+
+impl DownloadPool {
+    pub fn new(workers: usize) -> Self {
+        let pool = DownloadPool {
+            state: Arc::new((Mutex::new(PoolState::default()), Condvar::new())),
+        };
+        for _ in 0..workers.max(1) {
+            let state = pool.state.clone();
+            std::thread::spawn(move || loop {
+                let job = {
+                    let (lock, condvar) = &*state;
+                    let mut state = lock.lock().unwrap();
+                    while state.pending.is_empty() && !state.shutdown {
+                        state = condvar.wait(state).unwrap();
+                    }
+                    if state.shutdown {
+                        return;
+                    }
+                    let next = state
+                        .pending
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, a), (_, b)| {
+                            a.priority
+                                .cmp(&b.priority)
+                                .then(b.sequence.cmp(&a.sequence))
+                        })
+                        .map(|(index, _)| index)
+                        .unwrap();
+                    state.pending.remove(next).job
+                };
+                job();
+            });
+        }
+        pool
+    }
+
+    pub fn submit(&self, priority: u64, job: impl FnOnce() + Send + 'static) {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.pending.push(PendingJob {
+            priority,
+            sequence,
+            job: Box::new(job),
+        });
+        condvar.notify_one();
+    }
+
+    /// Lets the workers exit, jobs that have not started are dropped
+    pub fn shutdown(&self) {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.shutdown = true;
+        state.pending.clear();
+        condvar.notify_all();
+    }
+}
+
+/// Stops the yt-dlp processes of a download and removes their partial files.
+/// Clones share state, so a handle can be kept after the Downloader is moved.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+    children: Arc<Mutex<HashMap<String, Child>>>, // Running yt-dlp processes by output name
+    started: Arc<Mutex<Vec<String>>>,             // Every output name used so far
+    download_directory: String,
+}
+
+impl CancelHandle {
+    pub fn new(download_directory: String) -> Self {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            children: Arc::new(Mutex::new(HashMap::new())),
+            started: Arc::new(Mutex::new(vec![])),
+            download_directory,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Keeps track of a running process, kills it right away if the download was cancelled
+    pub fn register(&self, name: &str, mut child: Child) -> bool {
+        self.started.lock().unwrap().push(name.to_string());
+        let mut children = self.children.lock().unwrap();
+        if self.is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return false;
+        }
+        children.insert(name.to_string(), child);
+        true
+    }
+
+    /// Records a download without a process, so cancelling still removes its partial files.
+    /// False if the download was already cancelled.
+    pub fn start(&self, name: &str) -> bool {
+        self.started.lock().unwrap().push(name.to_string());
+        !self.is_cancelled()
+    }
+
+    /// Hands the process back once its output is done, None if it was cancelled meanwhile
+    pub fn finish(&self, name: &str) -> Option<Child> {
+        self.children.lock().unwrap().remove(name)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        for (name, mut child) in self.children.lock().unwrap().drain() {
+            debug!("Killing yt-dlp for {}", name);
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let started = self.started.lock().unwrap();
+        let paths = match fs::read_dir(&self.download_directory) {
+            Ok(value) => value,
+            Err(error) => {
+                warn!("Could not clean up {}: {}", self.download_directory, error);
+                return;
+            }
+        };
+        for path in paths.filter_map(Result::ok).map(|entry| entry.path()) {
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let partial = file_name.ends_with(".part")
+                || file_name.ends_with(".ytdl")
+                || file_name.contains(".part-Frag");
+            // Only the name followed by its extension, "abc" must not match "abcd.webm.part"
+            let own = |name: &String| {
+                file_name
+                    .strip_prefix(name.as_str())
+                    .map_or(false, |rest| rest.starts_with('.'))
+            };
+            if partial && started.iter().any(own) {
+                debug!("Removing partial download {:?}", path);
+                if let Err(error) = fs::remove_file(&path) {
+                    warn!("Could not remove {:?}: {}", path, error);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct DownloadedObject {
     name: String,
     path: String,
+    container: Option<String>, // File extension, e.g. "mkv" or "webm"
+    duration: Option<f64>,     // Seconds
+}
+
+impl DownloadedObject {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn container(&self) -> Option<&str> {
+        self.container.as_deref()
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        self.duration
+    }
+}
+
+/// A video that could not be downloaded, after all retries
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadFailure {
+    pub name: String,
+    pub url: String,
+    pub error: String,
+    pub stderr: String, // Last lines of yt-dlp's error output
+    pub attempts: u32,
+}
+
+// Videos that were downloaded and those that failed
+type DownloadResults = (Vec<DownloadedObject>, Vec<DownloadFailure>);
+type VideoResult = Result<DownloadedObject, DownloadFailure>;
+
+/// How often a failed download is tried again, and how long to wait before that
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration, // Doubled after every failed attempt
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Time to wait after the given failed attempt, starting at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+}
+
+/// Progress of a single video as reported by yt-dlp with --newline
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadProgress {
+    pub name: String,
+    pub percent: f64,
+    pub total_bytes: Option<u64>,
+    pub downloaded_bytes: Option<u64>,
+    pub speed: Option<u64>, // Bytes per second
+    pub eta: Option<u64>,   // Seconds
+}
+
+impl DownloadProgress {
+    pub fn describe(&self) -> String {
+        let mut description = format!("{:.1}%", self.percent);
+        if let Some(total) = self.total_bytes {
+            description += &format!(" of {}", format_bytes(total));
+        }
+        if let Some(speed) = self.speed {
+            description += &format!(" at {}/s", format_bytes(speed));
+        }
+        if let Some(eta) = self.eta {
+            description += &format!(" ETA {:02}:{:02}", eta / 60, eta % 60);
+        }
+        description
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, units[unit])
+}
+
+// "10.00MiB", "1.5KB" or "512B", None for yt-dlp's "Unknown"
+fn parse_bytes(field: &str) -> Option<u64> {
+    let field = field.trim_start_matches('~');
+    let split = field.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = field.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let multiplier: f64 = match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        _ => return None,
+    };
+    Some((number * multiplier) as u64)
+}
+
+// "05", "01:05" or "1:01:05"
+fn parse_duration(field: &str) -> Option<u64> {
+    let mut seconds = 0;
+    for part in field.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// Parses a yt-dlp progress line such as
+/// "[download]  23.5% of ~ 10.00MiB at  1.23MiB/s ETA 00:05".
+/// Other output lines return None.
+pub fn parse_progress_line(line: &str) -> Option<DownloadProgress> {
+    let line = line.trim().strip_prefix("[download]")?;
+    let mut fields = line.split_whitespace().filter(|field| *field != "~");
+    let percent = fields.next()?.strip_suffix('%')?.parse::<f64>().ok()?;
+    let mut progress = DownloadProgress {
+        percent,
+        ..Default::default()
+    };
+    while let Some(field) = fields.next() {
+        match field {
+            "of" => progress.total_bytes = fields.next().and_then(parse_bytes),
+            "at" => {
+                progress.speed = fields
+                    .next()
+                    .and_then(|speed| speed.strip_suffix("/s"))
+                    .and_then(parse_bytes)
+            }
+            "ETA" => progress.eta = fields.next().and_then(parse_duration),
+            _ => {}
+        }
+    }
+    if let Some(total) = progress.total_bytes {
+        progress.downloaded_bytes = Some((total as f64 * percent / 100.0) as u64);
+    }
+    Some(progress)
+}
+
+// Content types a direct media link may be served with, parameters already stripped
+fn is_media_content_type(content_type: &str) -> bool {
+    content_type.starts_with("video/")
+        || content_type.starts_with("audio/")
+        || [
+            "application/octet-stream",
+            "binary/octet-stream",
+            "application/mp4",
+            "application/ogg",
+            "application/x-matroska",
+        ]
+        .contains(&content_type)
+}
+
+// "bytes 100-199/200" into the first byte and the total size, if the total is known
+fn parse_content_range(header: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = header.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+/// Downloads a plain media file over http(s) into "<name>.<extension>".
+/// The data goes to a ".part" file first, a later attempt continues it with a Range request.
+pub fn download_http(
+    url: &str,
+    target: &DownloadTarget,
+    on_progress: &dyn Fn(DownloadProgress),
+) -> Result<FinalFile, DownloadError> {
+    if !target.cancel_handle.start(target.name) {
+        return Err(DownloadError::new("Cancelled".to_string()));
+    }
+    let container = url_extension(url).unwrap_or_else(|| "bin".to_string());
+    let file_path = format!("{}/{}.{}", target.directory, target.name, container);
+    let part_path = file_path.clone() + ".part";
+    let write_error = |error: io::Error| DownloadError::new(format!("{}: {}", part_path, error));
+    let resume_from = fs::metadata(&part_path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+
+    // A read timeout instead of a total one, large files take as long as they take
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(15))
+        .timeout_read(Duration::from_secs(30))
+        .build();
+    let mut request = agent.get(url);
+    if resume_from > 0 {
+        debug!("Resuming {} from byte {}", url, resume_from);
+        request = request.set("Range", &format!("bytes={}-", resume_from));
+    }
+    let response = match request.call() {
+        Ok(value) => value,
+        Err(ureq::Error::Status(416, _)) => {
+            // The partial file does not match what the server has, start over next time
+            let _ = fs::remove_file(&part_path);
+            return Err(DownloadError::new(
+                "Server rejected the resume range".to_string(),
+            ));
+        }
+        Err(error) => return Err(DownloadError::new(format!("Request failed: {}", error))),
+    };
+    let content_type = response
+        .header("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase());
+    if let Some(content_type) = content_type {
+        if !is_media_content_type(&content_type) {
+            return Err(DownloadError::new(format!(
+                "Unexpected Content-Type {}",
+                content_type
+            )));
+        }
+    }
+    let content_length = response
+        .header("Content-Length")
+        .and_then(|value| value.parse::<u64>().ok());
+    let range = response
+        .header("Content-Range")
+        .and_then(parse_content_range);
+    // Servers that ignore the range send the whole file again
+    let (offset, total_bytes) = match (response.status(), range) {
+        (206, Some((start, total))) if start == resume_from => {
+            (start, total.or(content_length.map(|length| start + length)))
+        }
+        (206, _) => {
+            let _ = fs::remove_file(&part_path);
+            return Err(DownloadError::new(
+                "Server resumed at the wrong position".to_string(),
+            ));
+        }
+        _ => (0, content_length),
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(&part_path)
+        .map_err(write_error)?;
+
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut downloaded = offset;
+    let started = Instant::now();
+    loop {
+        if target.cancel_handle.is_cancelled() {
+            return Err(DownloadError::new("Cancelled".to_string()));
+        }
+        let read = reader
+            .read(&mut buffer)
+            .map_err(|error| DownloadError::new(format!("Download failed: {}", error)))?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read]).map_err(write_error)?;
+        downloaded += read as u64;
+        // Speed of this attempt only, resumed bytes came in earlier
+        let elapsed = started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            Some(((downloaded - offset) as f64 / elapsed) as u64)
+        } else {
+            None
+        };
+        on_progress(DownloadProgress {
+            percent: total_bytes
+                .map_or(0.0, |total| downloaded as f64 * 100.0 / total.max(1) as f64),
+            total_bytes,
+            downloaded_bytes: Some(downloaded),
+            speed,
+            eta: match (total_bytes, speed) {
+                (Some(total), Some(speed)) if speed > 0 => {
+                    Some(total.saturating_sub(downloaded) / speed)
+                }
+                _ => None,
+            },
+            ..Default::default()
+        });
+    }
+    if let Some(total) = total_bytes {
+        if downloaded != total {
+            return Err(DownloadError::new(format!(
+                "Download stopped at {} of {} bytes",
+                downloaded, total
+            )));
+        }
+    }
+    fs::rename(&part_path, &file_path).map_err(write_error)?;
+    Ok(FinalFile {
+        path: file_path,
+        container: Some(container),
+        duration: None,
+    })
+}
+
+// Waits in small steps so cancelling does not have to wait for the whole backoff
+fn wait_unless_cancelled(cancel_handle: &CancelHandle, duration: Duration) -> bool {
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::ZERO;
+    while waited < duration {
+        if cancel_handle.is_cancelled() {
+            return false;
+        }
+        std::thread::sleep(step);
+        waited += step;
+    }
+    !cancel_handle.is_cancelled()
+}
+
+/// Where a Downloader puts its files and which backend it uses to get them
+#[derive(Debug, Clone)]
+pub struct DownloadSettings {
+    pub directory: String,
+    pub cache: Option<SharedCache>,
+    pub format: FormatOptions,
+    pub retry: RetryPolicy,
+    pub resolver: Arc<dyn MediaResolver>,
 }
 
 impl Downloader {
-    pub fn new(url: String, download_directory: String) -> Result<Self, io::Error> {
-        let (tx, rx): (
-            Sender<Vec<DownloadedObject>>,
-            Receiver<Vec<DownloadedObject>>,
-        ) = channel();
+    /// Resolves the url and downloads its videos on the pool.
+    /// Videos of a playlist keep their order among downloads with the same priority.
+    /// With a cache, videos already downloaded are reused and new downloads are added to it.
+    /// Failed attempts are retried according to the retry policy.
+    pub fn new(
+        url: String,
+        pool: &DownloadPool,
+        priority: u64,
+        settings: &DownloadSettings,
+    ) -> Result<Self, io::Error> {
+        let (tx, rx): (Sender<DownloadResults>, Receiver<DownloadResults>) = channel();
+        let progress = Arc::new(Mutex::new(HashMap::new()));
+        let cancel_handle = CancelHandle::new(settings.directory.clone());
+        let pool = pool.clone();
+        let settings = settings.clone();
         let downloader = Downloader {
             finished: false,
             failed: false,
-            url: url.clone(),
-            download_directory: download_directory.clone(),
+            download_directory: settings.directory.clone(),
+            cache: settings.cache.clone(),
             finished_notifier: rx,
             downloaded_objects: vec![],
+            failures: vec![],
+            progress: progress.clone(),
+            cancel_handle: cancel_handle.clone(),
         };
         std::thread::spawn(move || {
-            let output = YoutubeDl::new(url.clone()).socket_timeout("15").run();
-            debug!("Output object {:?}", output);
-            if output.is_ok() {
-                let youtube_obj = output.unwrap();
-                let mut video_array: Vec<youtube_dl::SingleVideo> = vec![];
-                let mut is_playlist = false;
-                match youtube_obj {
-                    YoutubeDlOutput::SingleVideo(value) => {
-                        let video = *value;
-                        video_array.push(video);
-                        debug!("Youtube singel video object");
+            let retry = settings.retry;
+            let mut attempt = 1;
+            let resolution = loop {
+                match settings.resolver.resolve(&url, false) {
+                    Ok(value) => break value,
+                    Err(error) if attempt > retry.retries => {
+                        warn!("Could not resolve {:?}: {}", url, error);
+                        let failure = DownloadFailure {
+                            name: url.clone(),
+                            url: url.clone(),
+                            error: format!("Could not resolve: {}", error),
+                            stderr: String::new(),
+                            attempts: attempt,
+                        };
+                        let _ = tx.send((vec![], vec![failure]));
+                        return;
                     }
-                    YoutubeDlOutput::Playlist(value) => {
-                        let playlist = *value;
-                        is_playlist = true;
-                        for video in playlist.entries.unwrap() {
-                            video_array.push(video);
+                    Err(error) => {
+                        debug!("Resolving {} failed, retrying: {}", url, error);
+                        if !wait_unless_cancelled(&cancel_handle, retry.delay(attempt)) {
+                            return;
                         }
-                        debug!("Youtube playlist object");
+                        attempt += 1;
                     }
                 }
+            };
+            debug!("Resolved {} into {:?}", url, resolution);
+            if cancel_handle.is_cancelled() {
+                return;
+            }
 
-                let mut worker_array = vec![];
-                let mut return_array = vec![];
-
-                for video in video_array {
-                    let (tx_worker, rx_worker): (
-                        Sender<DownloadedObject>,
-                        Receiver<DownloadedObject>,
-                    ) = channel();
-                    let tx_worker = tx_worker.clone();
-                    let download_directory = download_directory.clone();
-                    std::thread::spawn(move || {
-                        debug!(
-                            "Downloading {}",
-                            &video.title.clone().expect("Could not extract video title")
-                        );
-                        let download_path = path::Path::new(&download_directory);
-                        let uuid_video = Uuid::new_v4();
-                        let name = uuid_video.to_string();
-                        let output =
-                            download_path.to_str().unwrap().to_string() + "/" + &name + ".%(ext)s";
-                        let feedback = Command::new("yt-dlp")
-                            .args(&["-o", &output, &video.webpage_url.unwrap(), "-i"])
-                            .output()
-                            .expect("yt-dlp command failed hard!");
-                        let status_stdout = String::from_utf8_lossy(&feedback.stdout);
-                        // Downloaddir/(original_queueid)-(arraypos).(format)
-                        let mut resulting_video_path: String = "".to_string();
-                        let paths = fs::read_dir(download_path).unwrap();
-                        // Hacky way of finding the resulting filename
-                        for path in paths {
-                            let tmp_paht = path.unwrap().path();
-                            let res_path = tmp_paht.to_str().unwrap();
-                            if res_path.contains(&name) {
-                                resulting_video_path = res_path.to_string();
+            let (tx_worker, rx_worker): (Sender<VideoResult>, Receiver<VideoResult>) = channel();
+            for media in resolution.entries {
+                let tx_worker = tx_worker.clone();
+                let progress = progress.clone();
+                let cancel_handle = cancel_handle.clone();
+                let settings = settings.clone();
+                pool.submit(priority, move || {
+                    if cancel_handle.is_cancelled() {
+                        return;
+                    }
+                    let title = media.title.clone().unwrap_or_else(|| media.url.clone());
+                    let key = media.cache_key() + &settings.format.cache_suffix();
+                    if let Some(cache) = &settings.cache {
+                        if let Some(cached_path) = cache.lock().unwrap().lookup(&key) {
+                            debug!("Using cached {} for {}", cached_path, &title);
+                            let container = path::Path::new(&cached_path)
+                                .extension()
+                                .map(|value| value.to_string_lossy().to_string());
+                            let _ = tx_worker.send(Ok(DownloadedObject {
+                                name: title,
+                                path: cached_path,
+                                container,
+                                duration: media.duration,
+                            }));
+                            return;
+                        }
+                    }
+                    debug!("Downloading {}", &title);
+                    // Cached files are named after their key, others only need to be unique
+                    let name = match settings.cache {
+                        Some(_) => key.clone(),
+                        None => Uuid::new_v4().to_string(),
+                    };
+                    let target = DownloadTarget {
+                        directory: &settings.directory,
+                        name: &name,
+                        format: &settings.format,
+                        cancel_handle: &cancel_handle,
+                    };
+                    let on_progress = |mut update: DownloadProgress| {
+                        update.name = title.clone();
+                        progress.lock().unwrap().insert(media.url.clone(), update);
+                    };
+                    let mut attempt = 1;
+                    let result = loop {
+                        match settings.resolver.download(&media, &target, &on_progress) {
+                            Ok(file) => break Ok(file),
+                            Err(_) if cancel_handle.is_cancelled() => return,
+                            Err(failure) if attempt > retry.retries => {
+                                warn!(
+                                    "Downloading {} failed: {}\n{}",
+                                    media.url, failure.error, failure.stderr
+                                );
+                                break Err(DownloadFailure {
+                                    name: title.clone(),
+                                    url: media.url.clone(),
+                                    error: failure.error,
+                                    stderr: failure.stderr,
+                                    attempts: attempt,
+                                });
+                            }
+                            Err(failure) => {
+                                debug!(
+                                    "Downloading {} failed, retrying: {}",
+                                    media.url, failure.error
+                                );
+                                if !wait_unless_cancelled(&cancel_handle, retry.delay(attempt)) {
+                                    return;
+                                }
+                                attempt += 1;
                             }
                         }
-
-                        let download_object = DownloadedObject {
-                            name: video.title.clone().expect("Could not parse title"),
-                            path: resulting_video_path,
-                        };
-                        debug!("Resulting object {:?}", download_object);
-                        tx_worker
-                            .send(download_object)
-                            .expect("Failed to send download object");
-                    });
-                    worker_array.push(rx_worker);
-                }
-                while !worker_array.is_empty() {
-                    for (index, worker) in worker_array.iter().enumerate() {
-                        let result = worker.try_recv();
-                        if result.is_ok() {
-                            let download_object = result.unwrap();
-                            debug!("Downloaded object {:?}", download_object);
-                            return_array.push(download_object);
-                            worker_array.remove(index);
-                            break;
+                    };
+                    let result = result.map(|file| {
+                        // Files outside the download directory, e.g. local media, are not ours to evict
+                        let owned = path::Path::new(&file.path).starts_with(&settings.directory);
+                        if let (Some(cache), true) = (&settings.cache, owned) {
+                            let now = chrono::Local::now().timestamp();
+                            cache.lock().unwrap().insert(&key, &file.path, now);
                         }
-                    }
+                        DownloadedObject {
+                            name: title.clone(),
+                            path: file.path,
+                            container: file.container,
+                            duration: file.duration.or(media.duration),
+                        }
+                    });
+                    debug!("Resulting object {:?}", result);
+                    let _ = tx_worker.send(result);
+                });
+            }
+            // Every worker holds a sender, so this ends once all of them are done
+            drop(tx_worker);
+            let mut return_array = vec![];
+            let mut failures = vec![];
+            for result in rx_worker.iter() {
+                match result {
+                    Ok(download_object) => return_array.push(download_object),
+                    Err(failure) => failures.push(failure),
                 }
-                tx.send(return_array).unwrap();
-            } else {
-                // Check and download other types of media
-                warn!("yt-dlp did not like {:?}", url);
-                tx.send(vec![]).unwrap();
             }
+            if cancel_handle.is_cancelled() {
+                return;
+            }
+            let _ = tx.send((return_array, failures));
         });
 
         Ok(downloader)
     }
 
+    /// Returns the downloaded videos once, when all of them are done.
+    /// Videos that could not be downloaded are listed by failures.
     pub fn check_download_ready(&mut self) -> Option<Vec<DownloadedObject>> {
         match self.finished_notifier.try_recv() {
-            Ok(value) => {
-                self.downloaded_objects = value.clone();
+            Ok((downloaded, failures)) => {
+                self.downloaded_objects = downloaded.clone();
                 self.finished = true;
-                self.failed = false;
-                Some(value)
+                self.failed = !failures.is_empty();
+                self.failures = failures;
+                Some(downloaded)
             }
             Err(_) => None,
         }
     }
 
-    pub fn cleanup_downloaded_videoes(&self) -> () {
-        for video in self.downloaded_objects.clone() {
-            fs::remove_file(video.path).unwrap();
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn failures(&self) -> &[DownloadFailure] {
+        &self.failures
+    }
+
+    /// First downloaded file that exists on disk, once the download has finished
+    pub fn local_path(&mut self) -> Option<String> {
+        if !self.finished {
+            self.check_download_ready();
+        }
+        self.downloaded_objects
+            .iter()
+            .map(|video| video.path.clone())
+            .find(|video_path| !video_path.is_empty() && path::Path::new(video_path).exists())
+    }
+
+    /// Short progress text for status displays
+    pub fn describe_progress(&self) -> String {
+        if self.finished {
+            return match (self.downloaded_objects.len(), self.failures.first()) {
+                (0, Some(failure)) => format!("Failed: {}", failure.error),
+                (_, Some(_)) => format!("Downloaded, {} failed", self.failures.len()),
+                _ => "Downloaded".to_string(),
+            };
+        }
+        let progress = self.progress();
+        match progress.len() {
+            0 => "Waiting".to_string(),
+            1 => progress[0].describe(),
+            count => format!(
+                "{:.1}% of {} videos",
+                self.total_progress().unwrap_or(0.0),
+                count
+            ),
+        }
+    }
+
+    /// Kills running yt-dlp processes and removes partial files, finished files are kept
+    pub fn cancel(&self) {
+        self.cancel_handle.cancel();
+    }
+
+    /// Progress of every video that has started downloading
+    pub fn progress(&self) -> Vec<DownloadProgress> {
+        let mut progress: Vec<DownloadProgress> =
+            self.progress.lock().unwrap().values().cloned().collect();
+        progress.sort_by(|a, b| a.name.cmp(&b.name));
+        progress
+    }
+
+    /// Combined percentage of all videos that have started downloading
+    pub fn total_progress(&self) -> Option<f64> {
+        let progress = self.progress.lock().unwrap();
+        if progress.is_empty() {
+            return None;
+        }
+        let sum: f64 = progress.values().map(|entry| entry.percent).sum();
+        Some(sum / progress.len() as f64)
+    }
+
+    /// Removes the downloaded files once they were played. Files the cache keeps for
+    /// queueing them again are evicted by it, local files are played in place.
+    pub fn cleanup_downloaded_videoes(&self) {
+        for video in &self.downloaded_objects {
+            if !path::Path::new(&video.path).starts_with(&self.download_directory) {
+                continue;
+            }
+            if let Some(cache) = &self.cache {
+                if cache.lock().unwrap().contains(&video.path) {
+                    continue;
+                }
+            }
+            if let Err(error) = fs::remove_file(&video.path) {
+                warn!("Could not remove {}: {}", video.path, error);
+            }
         }
     }
 }
@@ -156,69 +828,364 @@ impl Downloader {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use log::LevelFilter;
-    use log4rs::append::console::ConsoleAppender;
-    use log4rs::config::{Appender, Config, Root};
+    use crate::daemon_cache::DownloadCache;
+    use crate::media_resolver::{Resolution, ScriptedResolver};
+    use std::process::Command;
+
+    #[test]
+    fn test_parse_progress_line() {
+        let progress =
+            parse_progress_line("[download]  23.5% of ~ 10.00MiB at  1.50MiB/s ETA 01:05").unwrap();
+        assert_eq!(progress.percent, 23.5);
+        assert_eq!(progress.total_bytes, Some(10 * 1024 * 1024));
+        assert_eq!(progress.speed, Some(1024 * 1024 * 3 / 2));
+        assert_eq!(progress.eta, Some(65));
+        assert_eq!(
+            progress.describe(),
+            "23.5% of 10.00MiB at 1.50MiB/s ETA 01:05"
+        );
 
-    fn init_log() {
-        let stdout = ConsoleAppender::builder().build();
+        let progress = parse_progress_line("[download] 100% of 2.00KiB in 00:00:01").unwrap();
+        assert_eq!(progress.percent, 100.0);
+        assert_eq!(progress.downloaded_bytes, Some(2048));
 
-        let config = Config::builder()
-            .appender(Appender::builder().build("stdout", Box::new(stdout)))
-            .build(Root::builder().appender("stdout").build(LevelFilter::Trace))
-            .unwrap();
+        let progress =
+            parse_progress_line("[download]   0.0% of Unknown at Unknown B/s ETA Unknown").unwrap();
+        assert_eq!(progress.total_bytes, None);
+        assert_eq!(progress.eta, None);
 
-        let _handle = log4rs::init_config(config).unwrap();
+        assert!(parse_progress_line("[download] Destination: /tmp/video.webm").is_none());
+        assert!(parse_progress_line("[youtube] 138ajKRMzIY: Downloading webpage").is_none());
     }
 
     #[test]
-    fn test_single_video() {
-        init_log();
-        let mut download = Downloader::new(
-            "https://www.youtube.com/watch?v=138ajKRMzIY".to_string(),
-            "/tmp/".to_string(),
-        )
-        .unwrap();
-        let mut result = vec![];
-        loop {
-            let res = download.check_download_ready();
-            if res.is_some() {
-                result = res.unwrap();
-                break;
+    fn test_retry_backoff_doubles() {
+        let retry = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_secs(2),
+        };
+        assert_eq!(retry.delay(1), Duration::from_secs(2));
+        assert_eq!(retry.delay(2), Duration::from_secs(4));
+        assert_eq!(retry.delay(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_cancel_kills_process_and_removes_partial_files() {
+        let directory = std::env::temp_dir().join(format!("smqueue-cancel-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        let name = Uuid::new_v4().to_string();
+        let partial = directory.join(name.clone() + ".webm.part");
+        let finished = directory.join(name.clone() + ".mkv");
+        let unrelated = directory.join("unrelated.webm.part");
+        let longer = directory.join(name.clone() + "-2.webm.part");
+        for file in [&partial, &finished, &unrelated, &longer] {
+            fs::write(file, b"data").unwrap();
+        }
+
+        let handle = CancelHandle::new(directory.to_str().unwrap().to_string());
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        assert!(handle.register(&name, child));
+        handle.cancel();
+
+        assert!(handle.is_cancelled());
+        assert!(handle.finish(&name).is_none());
+        assert!(!path::Path::new(&format!("/proc/{}", pid)).exists());
+        assert!(!partial.exists());
+        assert!(finished.exists());
+        assert!(unrelated.exists());
+        assert!(longer.exists());
+        // Processes started after cancelling are killed right away
+        let late = Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(!handle.register("late", late));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_pool_runs_highest_priority_first() {
+        let pool = DownloadPool::new(1);
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        // Occupies the only worker until the remaining jobs are queued
+        pool.submit(0, move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started_rx.recv().unwrap();
+
+        let order = Arc::new(Mutex::new(vec![]));
+        let (done_tx, done_rx) = channel();
+        for (priority, name) in [(10, "low"), (50, "high first"), (50, "high second")] {
+            let order = order.clone();
+            let done_tx = done_tx.clone();
+            pool.submit(priority, move || {
+                order.lock().unwrap().push(name);
+                done_tx.send(()).unwrap();
+            });
+        }
+        release_tx.send(()).unwrap();
+        for _ in 0..3 {
+            done_rx
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high first", "high second", "low"]
+        );
+        pool.shutdown();
+    }
+
+    fn scripted_settings(resolver: Arc<ScriptedResolver>) -> DownloadSettings {
+        let directory = std::env::temp_dir().join(format!("smqueue-download-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        DownloadSettings {
+            directory: directory.to_str().unwrap().to_string(),
+            cache: None,
+            format: FormatOptions::default(),
+            retry: RetryPolicy {
+                retries: 1,
+                backoff: Duration::from_millis(10),
+            },
+            resolver,
+        }
+    }
+
+    fn wait_until_ready(download: &mut Downloader) -> Vec<DownloadedObject> {
+        for _ in 0..100 {
+            if let Some(result) = download.check_download_ready() {
+                return result;
             }
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            std::thread::sleep(Duration::from_millis(50));
         }
-        println!("{:?}", result);
+        panic!("Download did not finish");
+    }
+
+    // Serves body for the given number of requests and records their Range headers.
+    // The first response is cut off after cut_after bytes, if set.
+    fn serve_file(
+        body: Vec<u8>,
+        content_type: &'static str,
+        requests: usize,
+        cut_after: Option<usize>,
+    ) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/clip.mp4", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let seen = ranges.clone();
+        std::thread::spawn(move || {
+            for (index, stream) in listener.incoming().take(requests).enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    io::BufRead::read_line(&mut reader, &mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+                seen.lock().unwrap().push(range.clone());
+                let start = range
+                    .as_ref()
+                    .and_then(|value| value.trim_end_matches('-').parse::<usize>().ok())
+                    .unwrap_or(0);
+                let mut head = match range {
+                    Some(_) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                    None => "HTTP/1.1 200 OK\r\n".to_string(),
+                };
+                head += &format!(
+                    "Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content_type,
+                    body.len() - start
+                );
+                let end = match (index, cut_after) {
+                    (0, Some(cut)) => cut,
+                    _ => body.len(),
+                };
+                stream.write_all(head.as_bytes()).unwrap();
+                let _ = stream.write_all(&body[start..end]);
+            }
+        });
+        (url, ranges)
+    }
+
+    fn http_target_directory() -> String {
+        let directory = std::env::temp_dir().join(format!("smqueue-http-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        directory.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_http_download_resumes_partial_file() {
+        let body: Vec<u8> = (0..200_000u32).map(|value| value as u8).collect();
+        let (url, ranges) = serve_file(body.clone(), "video/mp4", 2, Some(70_000));
+        let directory = http_target_directory();
+        let cancel_handle = CancelHandle::new(directory.clone());
+        let target = DownloadTarget {
+            directory: &directory,
+            name: "clip",
+            format: &FormatOptions::default(),
+            cancel_handle: &cancel_handle,
+        };
+        let last_progress = Mutex::new(None);
+        let on_progress = |update: DownloadProgress| *last_progress.lock().unwrap() = Some(update);
+
+        // The connection drops part way, the received bytes are kept
+        assert!(download_http(&url, &target, &on_progress).is_err());
+        let part = directory.clone() + "/clip.mp4.part";
+        assert_eq!(fs::metadata(&part).unwrap().len(), 70_000);
+
+        let file = download_http(&url, &target, &on_progress).unwrap();
+        assert_eq!(file.path, directory.clone() + "/clip.mp4");
+        assert_eq!(file.container, Some("mp4".to_string()));
+        assert_eq!(fs::read(&file.path).unwrap(), body);
+        assert!(!path::Path::new(&part).exists());
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            vec![None, Some("70000-".to_string())]
+        );
+        let progress = last_progress.lock().unwrap().clone().unwrap();
+        assert_eq!(progress.percent, 100.0);
+        assert_eq!(progress.total_bytes, Some(200_000));
+        assert_eq!(progress.downloaded_bytes, Some(200_000));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_http_download_rejects_web_pages() {
+        let (url, _) = serve_file(
+            b"<html></html>".to_vec(),
+            "text/html; charset=utf-8",
+            1,
+            None,
+        );
+        let directory = http_target_directory();
+        let cancel_handle = CancelHandle::new(directory.clone());
+        let target = DownloadTarget {
+            directory: &directory,
+            name: "page",
+            format: &FormatOptions::default(),
+            cancel_handle: &cancel_handle,
+        };
+        let error = download_http(&url, &target, &|_| {}).unwrap_err();
+        assert_eq!(error.error, "Unexpected Content-Type text/html");
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Some((100, Some(200)))
+        );
+        assert_eq!(parse_content_range("bytes 100-199/*"), Some((100, None)));
+        assert!(is_media_content_type("application/octet-stream"));
+        assert!(!is_media_content_type("application/json"));
+    }
+
+    #[test]
+    fn test_single_video() {
+        let url = "https://www.youtube.com/watch?v=138ajKRMzIY";
+        let resolver = ScriptedResolver::default()
+            .resolves(
+                url,
+                Resolution {
+                    entries: vec![ScriptedResolver::video("138ajKRMzIY", "Single")],
+                    ..Default::default()
+                },
+            )
+            .downloads("138ajKRMzIY", vec![Ok(b"video")]);
+        let settings = scripted_settings(Arc::new(resolver));
+        let mut download =
+            Downloader::new(url.to_string(), &DownloadPool::new(1), 0, &settings).unwrap();
+        let result = wait_until_ready(&mut download);
         assert_eq!(result.len(), 1);
+        assert_eq!(fs::read(&result[0].path).unwrap(), b"video");
+        assert_eq!(result[0].duration, Some(60.0));
+        assert_eq!(download.describe_progress(), "Downloaded");
         download.cleanup_downloaded_videoes();
         for video in result {
             assert!(!path::Path::new(&video.path).exists());
         }
+        fs::remove_dir_all(&settings.directory).unwrap();
     }
 
     #[test]
     fn test_playlist_video() {
-        init_log();
-        // Needs a playlist of stable videos
-        let mut download = Downloader::new(
-            "https://www.youtube.com/playlist?list=PLu0ehJFoscLibLNDyEXNlojl37IsemN4H".to_string(),
-            "/tmp/".to_string(),
-        )
-        .unwrap();
-        let mut result = vec![];
-        loop {
-            let res = download.check_download_ready();
-            if res.is_some() {
-                result = res.unwrap();
-                break;
-            }
-            std::thread::sleep(std::time::Duration::from_millis(100));
-        }
-        println!("{:?}", result);
-        assert_eq!(result.len(), 6);
-        download.cleanup_downloaded_videoes();
-        for video in result {
-            assert!(!path::Path::new(&video.path).exists());
-        }
+        let url = "https://www.youtube.com/playlist?list=demo";
+        let playlist = Resolution {
+            title: Some("Demo".to_string()),
+            playlist: true,
+            entries: vec![
+                ScriptedResolver::video("a", "First"),
+                ScriptedResolver::video("b", "Second"),
+                ScriptedResolver::video("c", "Third"),
+            ],
+        };
+        let resolver = Arc::new(
+            ScriptedResolver::default()
+                .resolves(url, playlist.clone())
+                .downloads("a", vec![Ok(b"first")])
+                .downloads("b", vec![Err("HTTP Error 503"), Ok(b"second")])
+                .downloads(
+                    "c",
+                    vec![Err("Video unavailable"), Err("Video unavailable")],
+                ),
+        );
+        let mut settings = scripted_settings(resolver.clone());
+        let cache = DownloadCache::open(path::Path::new(&settings.directory), u64::MAX)
+            .unwrap()
+            .shared();
+        settings.cache = Some(cache.clone());
+        let pool = DownloadPool::new(2);
+        let mut download = Downloader::new(url.to_string(), &pool, 0, &settings).unwrap();
+        let result = wait_until_ready(&mut download);
+        assert_eq!(result.len(), 2);
+        assert!(download.has_failed());
+        assert_eq!(
+            download.failures(),
+            &[DownloadFailure {
+                name: "Third".to_string(),
+                url: "https://www.youtube.com/watch?v=c".to_string(),
+                error: "Video unavailable".to_string(),
+                stderr: "ERROR: scripted failure".to_string(),
+                attempts: 2,
+            }]
+        );
+        assert_eq!(download.describe_progress(), "Downloaded, 1 failed");
+        assert_eq!(resolver.pending_downloads(), 0);
+
+        // Queueing the playlist again only downloads what is not cached
+        let resolver = Arc::new(
+            ScriptedResolver::default()
+                .resolves(url, playlist)
+                .downloads("c", vec![Ok(b"third")]),
+        );
+        settings.resolver = resolver.clone();
+        let mut again = Downloader::new(url.to_string(), &pool, 0, &settings).unwrap();
+        let mut paths: Vec<String> = wait_until_ready(&mut again)
+            .into_iter()
+            .map(|video| video.path)
+            .collect();
+        paths.sort();
+        assert_eq!(resolver.pending_downloads(), 0);
+        assert!(!again.has_failed());
+        assert_eq!(
+            paths,
+            ["a", "b", "c"]
+                .iter()
+                .map(|id| settings.directory.clone() + "/youtube-" + id + ".mp4")
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(cache.lock().unwrap().size(), 16);
+        fs::remove_dir_all(&settings.directory).unwrap();
+        pool.shutdown();
     }
 }
@@ -1,9 +1,53 @@
+use std::fs::File;
 use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use rand::Rng;
+
+use crate::media_resolver::is_direct_media_url;
+use crate::socket_com::{EntryType, FormatOptions, PlaylistOptions, Schedule};
 
 #[derive(Debug)]
 pub struct DaemonQueue {
     media_queue: Vec<QueueObject>,
     to_be_processed_queue: Vec<PreQueueObject>,
+    scheduled_queue: Vec<ScheduledObject>,
+    undo_journal: Vec<UndoEntry>,
+    next_id: u64,
+}
+
+/// How many destructive operations can be undone
+pub const UNDO_JOURNAL_SIZE: usize = 20;
+
+#[derive(Debug)]
+/// Enough of a destructive operation to put the queue back the way it was
+enum UndoEntry {
+    Removed {
+        index: usize,
+        entry: QueueObject,
+    },
+    RemovedScheduled {
+        index: usize,
+        entry: ScheduledObject,
+    },
+    Cleared {
+        entries: Vec<QueueObject>,
+        scheduled: Vec<ScheduledObject>,
+    },
+    Promoted {
+        id: u64,
+        from_index: usize,
+    },
+    RemovedGroup {
+        entries: Vec<(usize, QueueObject)>,
+        scheduled: Vec<(usize, ScheduledObject)>,
+    },
+    /// Previous order of the queue by id, and priorities that were changed
+    Reordered {
+        order: Vec<u64>,
+        priorities: Vec<(u64, u64)>,
+    },
 }
 
 #[derive(Debug)]
@@ -15,17 +59,21 @@ struct QueueObject {
     object_type: ObjectTypes,
     playback_postition: f64,
     settings: Settings,
+    playlist: Option<PlaylistOptions>, // Set for playlists that are not expanded yet
+    group: Option<u64>,                // Entries queued together, e.g. a playlist or directory
+    format: Option<FormatOptions>,     // None uses the daemon defaults
 }
 
-#[derive(Debug)]
-enum ObjectTypes {
+#[derive(Debug, Clone)]
+pub enum ObjectTypes {
     LocalFile,
     YoutubeVideo,
     FileStream,
+    YoutubePlaylist,
 }
 
-#[derive(Debug)]
-struct Settings {
+#[derive(Debug, Clone)]
+pub struct Settings {
     playback_speed: f64,
     audio_pitch_correction: bool,
     start_pos: f64,
@@ -38,15 +86,28 @@ struct Settings {
 pub struct InputObject {
     input_string: String,
     priority: u64,
+    object_type: ObjectTypes,
     settings: Settings,
+    playlist: Option<PlaylistOptions>,
+    group: Option<u64>,
+    format: Option<FormatOptions>,
 }
 
 #[derive(Debug)]
 struct PreQueueObject {
+    id: u64,
     input: InputObject,
     finished: bool,
 }
 
+#[derive(Debug)]
+/// Entry held back until its schedule is due
+struct ScheduledObject {
+    id: u64,
+    input: InputObject,
+    schedule: Schedule,
+}
+
 /*
 1. Input object recived
 2. Determines if it's a local file or downloadable file based on input
@@ -55,19 +116,636 @@ Alternaitve
 1. Get file to play, start downloading it and wait with mpv playing until the download daemon have started.
 */
 
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            playback_speed: 1.0,
+            audio_pitch_correction: true,
+            start_pos: 0.0,
+            duration: 0.0,
+        }
+    }
+}
+
+impl ObjectTypes {
+    pub fn from_entry_type(entry_type: &EntryType) -> Option<Self> {
+        match entry_type {
+            EntryType::YoutubeMedia => Some(ObjectTypes::YoutubeVideo),
+            EntryType::LocalMedia => Some(ObjectTypes::LocalFile),
+            EntryType::FileStream => Some(ObjectTypes::FileStream),
+            EntryType::YoutubePlaylist => Some(ObjectTypes::YoutubePlaylist),
+            _ => None,
+        }
+    }
+}
+
+impl InputObject {
+    pub fn new(input_string: String, priority: u64, object_type: ObjectTypes) -> Self {
+        InputObject {
+            input_string,
+            priority,
+            object_type,
+            settings: Settings::new(),
+            playlist: None,
+            group: None,
+            format: None,
+        }
+    }
+    pub fn new_playlist(url: String, priority: u64, options: PlaylistOptions) -> Self {
+        InputObject {
+            input_string: url,
+            priority,
+            object_type: ObjectTypes::YoutubePlaylist,
+            settings: Settings::new(),
+            playlist: Some(options),
+            group: None,
+            format: None,
+        }
+    }
+    pub fn with_group(mut self, group: Option<u64>) -> Self {
+        self.group = group;
+        self
+    }
+    pub fn with_format(mut self, format: Option<FormatOptions>) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl QueueObject {
+    fn from_input(id: u64, input: InputObject) -> Self {
+        let path = match input.object_type {
+            ObjectTypes::YoutubeVideo => {
+                // Youtube entries arrive as "<id or url> - <title>"
+                let key = match input.input_string.split_once(" - ") {
+                    Some((key, _)) => key,
+                    None => &input.input_string,
+                };
+                if key.starts_with("http") {
+                    key.to_string()
+                } else {
+                    "https://www.youtube.com/watch?v=".to_string() + key
+                }
+            }
+            _ => input.input_string.clone(),
+        };
+        QueueObject {
+            id,
+            priority: input.priority,
+            path,
+            title: input.input_string,
+            object_type: input.object_type,
+            playback_postition: 0.0,
+            settings: input.settings,
+            playlist: input.playlist,
+            group: input.group,
+            format: input.format,
+        }
+    }
+}
+
+// Type column of the queue file, "type[#group][@schedule]"
+fn type_field(
+    object_type: &ObjectTypes,
+    group: Option<u64>,
+    schedule: Option<&Schedule>,
+) -> String {
+    let mut field = format!("{:?}", object_type);
+    if let Some(group) = group {
+        field += &format!("#{}", group);
+    }
+    match schedule {
+        Some(Schedule::NotBefore(value)) => field += &format!("@after:{}", value),
+        Some(Schedule::StartAt(value)) => field += &format!("@at:{}", value),
+        None => {}
+    }
+    field
+}
+
 impl DaemonQueue {
     pub fn new() -> Result<Self, io::Error> {
         Ok(DaemonQueue {
             media_queue: vec![],
             to_be_processed_queue: vec![],
+            scheduled_queue: vec![],
+            undo_journal: vec![],
+            next_id: 0,
         })
     }
-    pub fn add_to_queue(&mut self, object: InputObject) {
+    fn allocate_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+    pub fn add_to_queue(&mut self, object: InputObject) -> u64 {
         // When you attempt to add a object to the queue it needs to be processed first
+        let id = self.allocate_id();
         self.to_be_processed_queue.push(PreQueueObject {
+            id,
             input: object,
             finished: false,
         });
+        id
+    }
+    pub fn add_scheduled(&mut self, object: InputObject, schedule: Schedule) -> u64 {
+        let id = self.allocate_id();
+        self.scheduled_queue.push(ScheduledObject {
+            id,
+            input: object,
+            schedule,
+        });
+        id
+    }
+    pub fn process_prequeue(&mut self) {
+        for pre_object in self.to_be_processed_queue.drain(..) {
+            let queue_object = QueueObject::from_input(pre_object.id, pre_object.input);
+            // The first entry is the one playing, higher priority goes first after it
+            let position = match self
+                .media_queue
+                .iter()
+                .skip(1)
+                .position(|entry| entry.priority < queue_object.priority)
+            {
+                Some(value) => value + 1,
+                None => self.media_queue.len(),
+            };
+            self.media_queue.insert(position, queue_object);
+        }
+    }
+    /// Moves scheduled entries that are due into the queue.
+    /// Returns true if a due entry preempts the current one, it is then placed first.
+    pub fn release_scheduled(&mut self, now: i64) -> bool {
+        let mut preempt = false;
+        let mut index = 0;
+        while index < self.scheduled_queue.len() {
+            if self.scheduled_queue[index].schedule.timestamp() > now {
+                index += 1;
+                continue;
+            }
+            let scheduled = self.scheduled_queue.remove(index);
+            match scheduled.schedule {
+                Schedule::NotBefore(_) => self.to_be_processed_queue.push(PreQueueObject {
+                    id: scheduled.id,
+                    input: scheduled.input,
+                    finished: false,
+                }),
+                Schedule::StartAt(_) => {
+                    self.media_queue
+                        .insert(0, QueueObject::from_input(scheduled.id, scheduled.input));
+                    preempt = true;
+                }
+            }
+        }
+        preempt
+    }
+    pub fn current_path(&self) -> Option<String> {
+        self.media_queue.first().map(|entry| entry.path.clone())
+    }
+    pub fn current_id(&self) -> Option<u64> {
+        self.media_queue.first().map(|entry| entry.id)
+    }
+    /// Format options of the current entry, None when it uses the defaults
+    pub fn current_format(&self) -> Option<FormatOptions> {
+        self.media_queue
+            .first()
+            .and_then(|entry| entry.format.clone())
+    }
+    /// Ids, urls and format options of the downloadable entries following the current one:
+    /// YouTube videos and streams of direct media links
+    pub fn upcoming_videos(&self, count: usize) -> Vec<(u64, String, Option<FormatOptions>)> {
+        self.media_queue
+            .iter()
+            .skip(1)
+            .take(count)
+            .filter(|entry| entry.playlist.is_none())
+            .filter(|entry| match entry.object_type {
+                ObjectTypes::YoutubeVideo => true,
+                ObjectTypes::FileStream => is_direct_media_url(&entry.path),
+                _ => false,
+            })
+            .map(|entry| (entry.id, entry.path.clone(), entry.format.clone()))
+            .collect()
+    }
+    /// True while the entry is queued or waiting for its scheduled time
+    pub fn contains(&self, id: u64) -> bool {
+        self.media_queue.iter().any(|entry| entry.id == id)
+            || self.scheduled_queue.iter().any(|entry| entry.id == id)
+    }
+    /// Url and options of the current entry if it is a playlist waiting to be expanded
+    pub fn current_playlist(&self) -> Option<(String, PlaylistOptions)> {
+        let entry = self.media_queue.first()?;
+        let options = entry.playlist.clone()?;
+        Some((entry.path.clone(), options))
+    }
+    /// Replaces the current playlist entry with its videos, keeping its priority
+    pub fn expand_current(&mut self, videos: Vec<String>) {
+        if self.media_queue.is_empty() {
+            return;
+        }
+        let playlist = self.media_queue.remove(0);
+        // The playlist id doubles as the group of its videos
+        let group = playlist.group.or(Some(playlist.id));
+        for (index, video) in videos.into_iter().enumerate() {
+            let id = self.allocate_id();
+            let input = InputObject::new(video, playlist.priority, ObjectTypes::YoutubeVideo)
+                .with_group(group)
+                .with_format(playlist.format.clone());
+            self.media_queue
+                .insert(index, QueueObject::from_input(id, input));
+        }
+    }
+    /// Drops the current entry, the next one becomes current
+    pub fn pop_current(&mut self) {
+        if !self.media_queue.is_empty() {
+            self.media_queue.remove(0);
+        }
+    }
+    /// Moves the current entry to the end of the queue
+    pub fn requeue_current(&mut self) {
+        if !self.media_queue.is_empty() {
+            let entry = self.media_queue.remove(0);
+            self.media_queue.push(entry);
+        }
+    }
+    /// Picks a random entry with the same priority as the current one and makes it current
+    pub fn shuffle_next(&mut self) {
+        let priority = match self.media_queue.first() {
+            Some(entry) => entry.priority,
+            None => return,
+        };
+        let group_size = self
+            .media_queue
+            .iter()
+            .take_while(|entry| entry.priority == priority)
+            .count();
+        let index = rand::thread_rng().gen_range(0..group_size);
+        let entry = self.media_queue.remove(index);
+        self.media_queue.insert(0, entry);
+    }
+    fn journal(&mut self, undo_entry: UndoEntry) {
+        if self.undo_journal.len() >= UNDO_JOURNAL_SIZE {
+            self.undo_journal.remove(0);
+        }
+        self.undo_journal.push(undo_entry);
+    }
+    pub fn remove(&mut self, id: u64) -> bool {
+        if let Some(index) = self.media_queue.iter().position(|entry| entry.id == id) {
+            let entry = self.media_queue.remove(index);
+            self.journal(UndoEntry::Removed { index, entry });
+            return true;
+        }
+        if let Some(index) = self.scheduled_queue.iter().position(|entry| entry.id == id) {
+            let entry = self.scheduled_queue.remove(index);
+            self.journal(UndoEntry::RemovedScheduled { index, entry });
+            return true;
+        }
+        false
+    }
+    /// Removes everything except the entry currently playing
+    pub fn clear(&mut self) {
+        let entries = if self.media_queue.len() > 1 {
+            self.media_queue.split_off(1)
+        } else {
+            vec![]
+        };
+        let scheduled: Vec<ScheduledObject> = self.scheduled_queue.drain(..).collect();
+        if !entries.is_empty() || !scheduled.is_empty() {
+            self.journal(UndoEntry::Cleared { entries, scheduled });
+        }
+    }
+    /// Moves the entry to the front of the queue so it becomes current
+    pub fn promote(&mut self, id: u64) -> bool {
+        match self.media_queue.iter().position(|entry| entry.id == id) {
+            Some(index) => {
+                let entry = self.media_queue.remove(index);
+                self.media_queue.insert(0, entry);
+                self.journal(UndoEntry::Promoted {
+                    id,
+                    from_index: index,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+    fn order(&self) -> Vec<u64> {
+        self.media_queue.iter().map(|entry| entry.id).collect()
+    }
+    // Puts the queue back in the given order, entries not in it keep their place at the end
+    fn restore_order(&mut self, order: &[u64]) {
+        self.media_queue.sort_by_key(|entry| {
+            order
+                .iter()
+                .position(|id| *id == entry.id)
+                .unwrap_or(order.len())
+        });
+    }
+    pub fn remove_group(&mut self, group: u64) -> bool {
+        let mut entries = vec![];
+        let mut index = 0;
+        let mut original_index = 0;
+        while index < self.media_queue.len() {
+            if self.media_queue[index].group == Some(group) {
+                entries.push((original_index, self.media_queue.remove(index)));
+            } else {
+                index += 1;
+            }
+            original_index += 1;
+        }
+        let mut scheduled = vec![];
+        let mut index = 0;
+        let mut original_index = 0;
+        while index < self.scheduled_queue.len() {
+            if self.scheduled_queue[index].input.group == Some(group) {
+                scheduled.push((original_index, self.scheduled_queue.remove(index)));
+            } else {
+                index += 1;
+            }
+            original_index += 1;
+        }
+        if entries.is_empty() && scheduled.is_empty() {
+            return false;
+        }
+        self.journal(UndoEntry::RemovedGroup { entries, scheduled });
+        true
+    }
+    /// Moves every entry of the group to the front of the queue, keeping their order
+    pub fn promote_group(&mut self, group: u64) -> bool {
+        let order = self.order();
+        let (mut members, rest): (Vec<QueueObject>, Vec<QueueObject>) = self
+            .media_queue
+            .drain(..)
+            .partition(|entry| entry.group == Some(group));
+        if members.is_empty() {
+            self.media_queue = rest;
+            return false;
+        }
+        members.extend(rest);
+        self.media_queue = members;
+        self.journal(UndoEntry::Reordered {
+            order,
+            priorities: vec![],
+        });
+        true
+    }
+    /// Changes the priority of every entry in the group and moves them accordingly.
+    /// The entry currently playing stays where it is.
+    pub fn set_group_priority(&mut self, group: u64, priority: u64) -> bool {
+        let order = self.order();
+        let mut priorities = vec![];
+        for entry in self.media_queue.iter_mut() {
+            if entry.group == Some(group) {
+                priorities.push((entry.id, entry.priority));
+                entry.priority = priority;
+            }
+        }
+        for entry in self.scheduled_queue.iter_mut() {
+            if entry.input.group == Some(group) {
+                priorities.push((entry.id, entry.input.priority));
+                entry.input.priority = priority;
+            }
+        }
+        if priorities.is_empty() {
+            return false;
+        }
+        // Same ordering as process_prequeue, stable so the group keeps its order
+        if self.media_queue.len() > 1 {
+            self.media_queue[1..].sort_by(|a, b| b.priority.cmp(&a.priority));
+        }
+        self.journal(UndoEntry::Reordered { order, priorities });
+        true
+    }
+    /// Reverts the most recent remove, clear or promote.
+    /// Returns false if there was nothing to undo or the undo changed nothing.
+    pub fn undo(&mut self) -> bool {
+        let undo_entry = match self.undo_journal.pop() {
+            Some(value) => value,
+            None => return false,
+        };
+        match undo_entry {
+            UndoEntry::Removed { index, entry } => {
+                let index = index.min(self.media_queue.len());
+                self.media_queue.insert(index, entry);
+            }
+            UndoEntry::RemovedScheduled { index, entry } => {
+                let index = index.min(self.scheduled_queue.len());
+                self.scheduled_queue.insert(index, entry);
+            }
+            UndoEntry::Cleared { entries, scheduled } => {
+                // Restored entries go before anything queued since the clear
+                let index = self.media_queue.len().min(1);
+                self.media_queue.splice(index..index, entries);
+                self.scheduled_queue.splice(0..0, scheduled);
+            }
+            UndoEntry::RemovedGroup { entries, scheduled } => {
+                for (index, entry) in entries {
+                    let index = index.min(self.media_queue.len());
+                    self.media_queue.insert(index, entry);
+                }
+                for (index, entry) in scheduled {
+                    let index = index.min(self.scheduled_queue.len());
+                    self.scheduled_queue.insert(index, entry);
+                }
+            }
+            UndoEntry::Reordered { order, priorities } => {
+                for (id, priority) in priorities {
+                    if let Some(entry) = self.media_queue.iter_mut().find(|entry| entry.id == id) {
+                        entry.priority = priority;
+                    }
+                    if let Some(entry) =
+                        self.scheduled_queue.iter_mut().find(|entry| entry.id == id)
+                    {
+                        entry.input.priority = priority;
+                    }
+                }
+                self.restore_order(&order);
+            }
+            UndoEntry::Promoted { id, from_index } => {
+                // Nothing to move back if the entry was played or removed since
+                let index = match self.media_queue.iter().position(|entry| entry.id == id) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                let entry = self.media_queue.remove(index);
+                let from_index = from_index.min(self.media_queue.len());
+                self.media_queue.insert(from_index, entry);
+            }
+        }
+        true
+    }
+    /// Writes the queue in the "id;priority;type;location" format read by the status watcher.
+    /// Group and schedule are appended to the type as "type#<group>@at:<unix time>".
+    pub fn write_queue_file(&self, file_path: &path::Path) -> Result<(), io::Error> {
+        let mut contents = String::new();
+        for entry in &self.media_queue {
+            contents += &format!(
+                "{};{};{};{}\n",
+                entry.id,
+                entry.priority,
+                type_field(&entry.object_type, entry.group, None),
+                entry.title
+            );
+        }
+        for entry in &self.scheduled_queue {
+            contents += &format!(
+                "{};{};{};{}\n",
+                entry.id,
+                entry.input.priority,
+                type_field(
+                    &entry.input.object_type,
+                    entry.input.group,
+                    Some(&entry.schedule)
+                ),
+                entry.input.input_string
+            );
+        }
+        let mut file = File::create(file_path)?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_file(name: &str, priority: u64) -> InputObject {
+        InputObject::new(name.to_string(), priority, ObjectTypes::LocalFile)
+    }
+
+    #[test]
+    fn test_not_before_waits_for_timestamp() {
+        let mut queue = DaemonQueue::new().unwrap();
+        queue.add_scheduled(local_file("/demo.mkv", 50), Schedule::NotBefore(1000));
+        assert!(!queue.release_scheduled(999));
+        queue.process_prequeue();
+        assert_eq!(queue.current_path(), None);
+        assert!(!queue.release_scheduled(1000));
+        queue.process_prequeue();
+        assert_eq!(queue.current_path(), Some("/demo.mkv".to_string()));
+    }
+
+    #[test]
+    fn test_start_at_preempts_current() {
+        let mut queue = DaemonQueue::new().unwrap();
+        queue.add_to_queue(local_file("/playing.mkv", 50));
+        queue.process_prequeue();
+        let id = queue.add_scheduled(local_file("/demo.mkv", 50), Schedule::StartAt(1000));
+        assert!(queue.release_scheduled(1000));
+        assert_eq!(queue.current_id(), Some(id));
+        queue.pop_current();
+        assert_eq!(queue.current_path(), Some("/playing.mkv".to_string()));
+    }
+
+    #[test]
+    fn test_shuffle_stays_within_priority() {
+        let mut queue = DaemonQueue::new().unwrap();
+        queue.add_to_queue(local_file("/a.mkv", 50));
+        queue.add_to_queue(local_file("/b.mkv", 50));
+        queue.add_to_queue(local_file("/c.mkv", 50));
+        queue.add_to_queue(local_file("/low.mkv", 10));
+        queue.process_prequeue();
+        for _ in 0..20 {
+            queue.shuffle_next();
+            assert_ne!(queue.current_path(), Some("/low.mkv".to_string()));
+        }
+        queue.requeue_current();
+        assert_eq!(queue.media_queue.last().unwrap().priority, 50);
+    }
+
+    #[test]
+    fn test_undo_remove_clear_and_promote() {
+        let mut queue = DaemonQueue::new().unwrap();
+        queue.add_to_queue(local_file("/a.mkv", 50));
+        let b = queue.add_to_queue(local_file("/b.mkv", 50));
+        let c = queue.add_to_queue(local_file("/c.mkv", 50));
+        queue.process_prequeue();
+        let order = |queue: &DaemonQueue| -> Vec<String> {
+            queue
+                .media_queue
+                .iter()
+                .map(|entry| entry.path.clone())
+                .collect()
+        };
+        let original = order(&queue);
+
+        assert!(queue.remove(b));
+        queue.clear();
+        assert_eq!(order(&queue), vec!["/a.mkv"]);
+        assert!(queue.undo());
+        assert_eq!(order(&queue), vec!["/a.mkv", "/c.mkv"]);
+        assert!(queue.undo());
+        assert_eq!(order(&queue), original);
+
+        assert!(queue.promote(c));
+        assert_eq!(queue.current_id(), Some(c));
+        assert!(queue.undo());
+        assert_eq!(order(&queue), original);
+        assert!(!queue.undo());
+
+        // A promoted entry that was played since can not be moved back
+        assert!(queue.promote(c));
+        queue.pop_current();
+        assert!(!queue.undo());
+    }
+
+    #[test]
+    fn test_group_promote_priority_and_remove() {
+        let mut queue = DaemonQueue::new().unwrap();
+        queue.add_to_queue(local_file("/playing.mkv", 50));
+        queue.add_to_queue(local_file("/other.mkv", 50));
+        queue.add_to_queue(local_file("/1.mkv", 50).with_group(Some(7)));
+        queue.add_to_queue(local_file("/2.mkv", 50).with_group(Some(7)));
+        queue.process_prequeue();
+        let order = |queue: &DaemonQueue| -> Vec<String> {
+            queue
+                .media_queue
+                .iter()
+                .map(|entry| entry.path.clone())
+                .collect()
+        };
+        let original = order(&queue);
+
+        assert!(queue.set_group_priority(7, 90));
+        assert_eq!(
+            order(&queue),
+            vec!["/playing.mkv", "/1.mkv", "/2.mkv", "/other.mkv"]
+        );
+        assert!(queue.undo());
+        assert_eq!(order(&queue), original);
+        assert_eq!(queue.media_queue[2].priority, 50);
+
+        assert!(queue.promote_group(7));
+        assert_eq!(queue.current_path(), Some("/1.mkv".to_string()));
+        assert!(queue.undo());
+        assert_eq!(order(&queue), original);
+
+        assert!(queue.remove_group(7));
+        assert_eq!(order(&queue), vec!["/playing.mkv", "/other.mkv"]);
+        assert!(queue.undo());
+        assert_eq!(order(&queue), original);
+    }
+
+    #[test]
+    fn test_playlist_expands_in_place() {
+        let mut queue = DaemonQueue::new().unwrap();
+        let url = "https://www.youtube.com/playlist?list=demo".to_string();
+        queue.add_to_queue(InputObject::new_playlist(
+            url.clone(),
+            50,
+            PlaylistOptions::default(),
+        ));
+        queue.add_to_queue(local_file("/after.mkv", 50));
+        queue.process_prequeue();
+        assert_eq!(queue.current_playlist().unwrap().0, url);
+        queue.expand_current(vec!["aaa - First".to_string(), "bbb - Second".to_string()]);
+        assert_eq!(queue.current_playlist(), None);
+        assert_eq!(
+            queue.current_path(),
+            Some("https://www.youtube.com/watch?v=aaa".to_string())
+        );
+        queue.pop_current();
+        queue.pop_current();
+        assert_eq!(queue.current_path(), Some("/after.mkv".to_string()));
     }
-    pub fn process_prequeue(&mut self) {}
 }
@@ -1,61 +1,1561 @@
-use std::process::Command;
-
-pub fn spotify_playing() -> bool {
-    let status = Command::new("dbus-send")
-        .args(&[
-            "--print-reply",
-            "--session",
-            "--dest=org.mpris.MediaPlayer2.spotify",
-            "/org/mpris/MediaPlayer2",
-            "org.freedesktop.DBus.Properties.Get",
-            "string:org.mpris.MediaPlayer2.Player",
-            "string:PlaybackStatus",
-        ])
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+
+/// Bus names of MPRIS players start with this, e.g. "org.mpris.MediaPlayer2.spotify"
+pub const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+/// Which MPRIS players the daemon may pause, by the name after MPRIS_PREFIX.
+/// An empty allow list allows every player, deny wins over allow.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayerFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl PlayerFilter {
+    pub fn allows(&self, bus_name: &str) -> bool {
+        let player = bus_name.strip_prefix(MPRIS_PREFIX).unwrap_or(bus_name);
+        // Players running more than once add ".instance<pid>" to their name
+        let matches = |name: &String| {
+            player == name
+                || player
+                    .strip_prefix(name.as_str())
+                    .map_or(false, |rest| rest.starts_with('.'))
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Playback state an MPRIS player reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "Playing" => Some(PlaybackStatus::Playing),
+            "Paused" => Some(PlaybackStatus::Paused),
+            "Stopped" => Some(PlaybackStatus::Stopped),
+            _ => None,
+        }
+    }
+}
+
+/// How long a call may take before the player is given up on, the mpv thread waits for it
+pub const MPRIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Connection to a session bus for talking to MPRIS players
+pub struct Mpris {
+    connection: Connection,
+    timeout: Duration,
+}
+
+impl fmt::Debug for Mpris {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mpris")
+            .field("name", &self.connection.unique_name().to_string())
+            .finish()
+    }
+}
+
+impl Mpris {
+    /// Connects to the session bus of the user
+    pub fn session() -> Result<Self, dbus::Error> {
+        Ok(Mpris {
+            connection: Connection::new_session()?,
+            timeout: MPRIS_TIMEOUT,
+        })
+    }
+
+    /// Connects to the bus at the address, e.g. a private dbus-daemon
+    #[cfg(test)]
+    pub fn with_address(address: &str) -> Result<Self, dbus::Error> {
+        let mut channel = dbus::channel::Channel::open_private(address)?;
+        channel.register()?;
+        Ok(Mpris {
+            connection: Connection::from(channel),
+            timeout: MPRIS_TIMEOUT,
+        })
+    }
+
+    /// Bus names of all MPRIS players on the bus
+    pub fn players(&self) -> Result<Vec<String>, dbus::Error> {
+        let proxy = self.connection.with_proxy(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            self.timeout,
+        );
+        let (names,): (Vec<String>,) =
+            proxy.method_call("org.freedesktop.DBus", "ListNames", ())?;
+        Ok(names
+            .into_iter()
+            .filter(|name| name.starts_with(MPRIS_PREFIX))
+            .collect())
+    }
+
+    pub fn playback_status(&self, bus_name: &str) -> Result<PlaybackStatus, dbus::Error> {
+        let proxy = self
+            .connection
+            .with_proxy(bus_name, MPRIS_PATH, self.timeout);
+        let status: String = proxy.get(MPRIS_PLAYER_INTERFACE, "PlaybackStatus")?;
+        PlaybackStatus::from_str(&status)
+            .ok_or_else(|| dbus::Error::new_failed(&format!("Unknown playback status {}", status)))
+    }
+
+    // Calls a method without arguments on the player interface, e.g. "Pause" or "Play"
+    fn call(&self, bus_name: &str, method: &str) -> Result<(), dbus::Error> {
+        let proxy = self
+            .connection
+            .with_proxy(bus_name, MPRIS_PATH, self.timeout);
+        proxy.method_call(MPRIS_PLAYER_INTERFACE, method, ())
+    }
+
+    pub fn pause(&self, bus_name: &str) -> Result<(), dbus::Error> {
+        self.call(bus_name, "Pause")
+    }
+
+    pub fn play(&self, bus_name: &str) -> Result<(), dbus::Error> {
+        self.call(bus_name, "Play")
+    }
+
+    /// Volume of the player, 0 is silent and 1 is full volume
+    pub fn volume(&self, bus_name: &str) -> Result<f64, dbus::Error> {
+        let proxy = self
+            .connection
+            .with_proxy(bus_name, MPRIS_PATH, self.timeout);
+        proxy.get(MPRIS_PLAYER_INTERFACE, "Volume")
+    }
+
+    pub fn set_volume(&self, bus_name: &str, volume: f64) -> Result<(), dbus::Error> {
+        let proxy = self
+            .connection
+            .with_proxy(bus_name, MPRIS_PATH, self.timeout);
+        proxy.set(MPRIS_PLAYER_INTERFACE, "Volume", volume)
+    }
+}
+
+/// Pauses the MPRIS players that play while we do and resumes only those afterwards.
+/// The bus connection is opened on first use and again after a failed call.
+#[derive(Debug)]
+pub struct MprisPlayers {
+    filter: PlayerFilter,
+    connection: Option<Mpris>,
+}
+
+impl MprisPlayers {
+    pub fn new(filter: PlayerFilter) -> Self {
+        MprisPlayers {
+            filter,
+            connection: None,
+        }
+    }
+
+    /// Uses the given connection instead of the session bus
+    #[cfg(test)]
+    pub fn with_connection(filter: PlayerFilter, connection: Mpris) -> Self {
+        MprisPlayers {
+            filter,
+            connection: Some(connection),
+        }
+    }
+
+    fn connection(&mut self) -> Option<&Mpris> {
+        if self.connection.is_none() {
+            match Mpris::session() {
+                Ok(value) => self.connection = Some(value),
+                Err(error) => log::warn!("Could not connect to the session bus: {}", error),
+            }
+        }
+        self.connection.as_ref()
+    }
+
+    /// Bus names of the allowed players that are playing
+    pub fn playing(&mut self) -> Vec<String> {
+        let filter = self.filter.clone();
+        let mpris = match self.connection() {
+            Some(value) => value,
+            None => return vec![],
+        };
+        let players = match mpris.players() {
+            Ok(value) => value,
+            Err(error) => {
+                log::warn!("Could not list MPRIS players: {}", error);
+                self.connection = None;
+                return vec![];
+            }
+        };
+        players
+            .into_iter()
+            .filter(|player| filter.allows(player))
+            .filter(|player| match mpris.playback_status(player) {
+                Ok(status) => status == PlaybackStatus::Playing,
+                Err(error) => {
+                    log::debug!("No playback status from {}: {}", player, error);
+                    false
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where the MPD server listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum MpdAddress {
+    Tcp(String, u16),
+    Socket(PathBuf),
+}
+
+/// MPD connection settings, by default taken from MPD_HOST and MPD_PORT like mpc does
+#[derive(Debug, Clone, PartialEq)]
+pub struct MpdConfig {
+    pub address: MpdAddress,
+    pub password: Option<String>,
+    pub timeout: Duration, // For connecting and for every reply
+}
+
+pub const DEFAULT_MPD_PORT: u16 = 6600;
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        MpdConfig::from_env()
+    }
+}
+
+impl MpdConfig {
+    pub fn from_env() -> Self {
+        MpdConfig::parse(
+            std::env::var("MPD_HOST").ok().as_deref(),
+            std::env::var("MPD_PORT").ok().as_deref(),
+        )
+    }
+
+    /// MPD_HOST is "[password@]host" where host may be a socket path, MPD_PORT a tcp port
+    pub fn parse(host: Option<&str>, port: Option<&str>) -> Self {
+        let host = host
+            .filter(|value| !value.is_empty())
+            .unwrap_or("localhost");
+        // Socket paths may contain "@" themselves, so only a leading password is split off
+        let (password, host) = match host.split_once('@') {
+            Some((password, rest)) if !password.is_empty() && !rest.is_empty() => {
+                (Some(password.to_string()), rest)
+            }
+            _ => (None, host),
+        };
+        let address = if host.starts_with('/') {
+            MpdAddress::Socket(PathBuf::from(host))
+        } else {
+            let port = port
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MPD_PORT);
+            MpdAddress::Tcp(host.to_string(), port)
+        };
+        MpdConfig {
+            address,
+            password,
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Playback state from MPD's status command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MpdState {
+    Play,
+    Pause,
+    Stop,
+}
+
+#[derive(Debug)]
+enum MpdStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for MpdStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MpdStream::Tcp(stream) => stream.read(buffer),
+            MpdStream::Unix(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for MpdStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            MpdStream::Tcp(stream) => stream.write(buffer),
+            MpdStream::Unix(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MpdStream::Tcp(stream) => stream.flush(),
+            MpdStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Minimal client for the MPD text protocol, enough to check, pause and resume playback
+#[derive(Debug)]
+pub struct MpdClient {
+    stream: BufReader<MpdStream>,
+}
+
+// Arguments are quoted, with backslashes and quotes escaped
+fn mpd_quote(argument: &str) -> String {
+    format!(
+        "\"{}\"",
+        argument.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+impl MpdClient {
+    /// Connects, checks the greeting and sends the password if there is one
+    pub fn connect(config: &MpdConfig) -> Result<Self, io::Error> {
+        let stream = match &config.address {
+            MpdAddress::Tcp(host, port) => {
+                let address = (host.as_str(), *port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("Unknown host {}", host))
+                    })?;
+                let stream = TcpStream::connect_timeout(&address, config.timeout)?;
+                stream.set_read_timeout(Some(config.timeout))?;
+                stream.set_write_timeout(Some(config.timeout))?;
+                MpdStream::Tcp(stream)
+            }
+            MpdAddress::Socket(socket_path) => {
+                let stream = UnixStream::connect(socket_path)?;
+                stream.set_read_timeout(Some(config.timeout))?;
+                stream.set_write_timeout(Some(config.timeout))?;
+                MpdStream::Unix(stream)
+            }
+        };
+        let mut client = MpdClient {
+            stream: BufReader::new(stream),
+        };
+        let greeting = client.read_line()?;
+        if !greeting.starts_with("OK MPD ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not an MPD server: {}", greeting),
+            ));
+        }
+        if let Some(password) = &config.password {
+            client.command(&format!("password {}", mpd_quote(password)))?;
+        }
+        Ok(client)
+    }
+
+    fn read_line(&mut self) -> Result<String, io::Error> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "MPD closed the connection",
+            ));
+        }
+        Ok(line.trim_end_matches('\n').to_string())
+    }
+
+    /// Sends a command and returns the "key: value" pairs of the reply
+    pub fn command(&mut self, command: &str) -> Result<Vec<(String, String)>, io::Error> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\n")?;
+        let mut pairs = vec![];
+        loop {
+            let line = self.read_line()?;
+            if line == "OK" {
+                return Ok(pairs);
+            }
+            if line.starts_with("ACK ") {
+                return Err(io::Error::new(io::ErrorKind::Other, line));
+            }
+            if let Some((key, value)) = line.split_once(": ") {
+                pairs.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+
+    pub fn state(&mut self) -> Result<MpdState, io::Error> {
+        let status = self.command("status")?;
+        let state = status
+            .iter()
+            .find(|(key, _)| key == "state")
+            .map(|(_, value)| value.as_str());
+        match state {
+            Some("play") => Ok(MpdState::Play),
+            Some("pause") => Ok(MpdState::Pause),
+            Some("stop") => Ok(MpdState::Stop),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown MPD state {:?}", state),
+            )),
+        }
+    }
+
+    pub fn pause(&mut self) -> Result<(), io::Error> {
+        self.command("pause 1").map(|_| ())
+    }
+
+    pub fn play(&mut self) -> Result<(), io::Error> {
+        self.command("play").map(|_| ())
+    }
+
+    /// Volume in percent, None if MPD has no mixer
+    pub fn volume(&mut self) -> Result<Option<u8>, io::Error> {
+        let status = self.command("status")?;
+        // MPD reports -1 without a mixer
+        Ok(status
+            .iter()
+            .find(|(key, _)| key == "volume")
+            .and_then(|(_, value)| value.parse::<u8>().ok()))
+    }
+
+    pub fn set_volume(&mut self, percent: u8) -> Result<(), io::Error> {
+        self.command(&format!("setvol {}", percent)).map(|_| ())
+    }
+}
+
+/// A program whose playback is paused while rustysm plays and resumed afterwards
+pub trait ExternalPlayer: fmt::Debug {
+    fn name(&self) -> String;
+    fn is_playing(&mut self) -> bool;
+    /// Pauses the player if it is playing and returns what was paused,
+    /// the player name or e.g. the bus names of several MPRIS players
+    fn pause_playing(&mut self) -> Vec<String>;
+    /// Resumes what pause_playing returned, unless it is no longer paused
+    fn resume(&mut self, paused: &[String]);
+    /// Volumes from 0 to 1 of what is playing, named like pause_playing names it.
+    /// What has no volume is listed without one, it is paused instead of ducked.
+    /// None if the player reports no volumes at all, all of it is paused then.
+    fn playing_volumes(&mut self) -> Option<Vec<(String, Option<f64>)>> {
+        None
+    }
+    /// Pauses some of what pause_playing would pause and returns what was paused
+    fn pause_targets(&mut self, _targets: &[String]) -> Vec<String> {
+        self.pause_playing()
+    }
+    fn volume(&mut self, _target: &str) -> Option<f64> {
+        None
+    }
+    fn set_volume(&mut self, _target: &str, _volume: f64) {}
+}
+
+impl ExternalPlayer for MprisPlayers {
+    fn name(&self) -> String {
+        "mpris".to_string()
+    }
+
+    fn is_playing(&mut self) -> bool {
+        !self.playing().is_empty()
+    }
+
+    fn pause_playing(&mut self) -> Vec<String> {
+        let playing = self.playing();
+        self.pause_targets(&playing)
+    }
+
+    fn pause_targets(&mut self, targets: &[String]) -> Vec<String> {
+        let mpris = match &self.connection {
+            Some(value) => value,
+            None => return vec![],
+        };
+        targets
+            .iter()
+            .filter(|player| {
+                log::info!("Pausing {}", player);
+                match mpris.pause(player) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        log::warn!("Could not pause {}: {}", player, error);
+                        false
+                    }
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn resume(&mut self, paused: &[String]) {
+        let mpris = match self.connection() {
+            Some(value) => value,
+            None => return,
+        };
+        for player in paused {
+            // Players the user stopped, started again or closed since are left alone
+            match mpris.playback_status(player) {
+                Ok(PlaybackStatus::Paused) => {}
+                Ok(status) => {
+                    log::info!("Not resuming {}, it is {:?}", player, status);
+                    continue;
+                }
+                Err(error) => {
+                    log::info!("Not resuming {}: {}", player, error);
+                    continue;
+                }
+            }
+            log::info!("Resuming {}", player);
+            if let Err(error) = mpris.play(player) {
+                log::warn!("Could not resume {}: {}", player, error);
+            }
+        }
+    }
+
+    fn playing_volumes(&mut self) -> Option<Vec<(String, Option<f64>)>> {
+        let playing = self.playing();
+        let mpris = self.connection.as_ref()?;
+        Some(
+            playing
+                .into_iter()
+                .map(|player| match mpris.volume(&player) {
+                    Ok(volume) => (player, Some(volume)),
+                    Err(error) => {
+                        log::info!("Not ducking {}, it has no volume: {}", player, error);
+                        (player, None)
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn volume(&mut self, target: &str) -> Option<f64> {
+        self.connection()?.volume(target).ok()
+    }
+
+    fn set_volume(&mut self, target: &str, volume: f64) {
+        if let Some(mpris) = self.connection() {
+            if let Err(error) = mpris.set_volume(target, volume) {
+                log::warn!("Could not set the volume of {}: {}", target, error);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MpdPlayer {
+    config: MpdConfig,
+    client: Option<MpdClient>, // Kept between calls, replaced after an error
+}
+
+impl MpdPlayer {
+    pub fn new(config: MpdConfig) -> Self {
+        MpdPlayer {
+            config,
+            client: None,
+        }
+    }
+
+    // Runs a request on the kept connection. MPD closes idle connections,
+    // so a kept connection that fails is replaced and the request tried once more.
+    fn request<T>(
+        &mut self,
+        request: impl Fn(&mut MpdClient) -> Result<T, io::Error>,
+    ) -> Result<T, io::Error> {
+        if let Some(client) = self.client.as_mut() {
+            match request(client) {
+                Ok(value) => return Ok(value),
+                Err(error) => {
+                    log::debug!("MPD connection failed, reconnecting: {}", error);
+                    self.client = None;
+                }
+            }
+        }
+        let mut client = MpdClient::connect(&self.config)?;
+        let result = request(&mut client);
+        if result.is_ok() {
+            self.client = Some(client);
+        }
+        result
+    }
+
+    /// State of MPD, None if it can not be reached
+    fn state(&mut self) -> Option<MpdState> {
+        match self.request(MpdClient::state) {
+            Ok(state) => Some(state),
+            Err(error) => {
+                log::debug!("No state from MPD: {}", error);
+                None
+            }
+        }
+    }
+}
+
+impl ExternalPlayer for MpdPlayer {
+    fn name(&self) -> String {
+        "mpd".to_string()
+    }
+
+    fn is_playing(&mut self) -> bool {
+        self.state() == Some(MpdState::Play)
+    }
+
+    fn pause_playing(&mut self) -> Vec<String> {
+        if !self.is_playing() {
+            return vec![];
+        }
+        if let Err(error) = self.request(MpdClient::pause) {
+            log::warn!("Could not pause MPD: {}", error);
+        }
+        vec![self.name()]
+    }
+
+    fn resume(&mut self, _paused: &[String]) {
+        match self.state() {
+            Some(MpdState::Pause) => {
+                if let Err(error) = self.request(MpdClient::play) {
+                    log::warn!("Could not resume MPD: {}", error);
+                }
+            }
+            state => log::info!("Not resuming mpd, its state is {:?}", state),
+        }
+    }
+
+    fn playing_volumes(&mut self) -> Option<Vec<(String, Option<f64>)>> {
+        if self.state()? != MpdState::Play {
+            return Some(vec![]);
+        }
+        // MPD without a mixer has no volume
+        let percent = self.request(MpdClient::volume).ok()?;
+        Some(vec![(
+            self.name(),
+            percent.map(|value| f64::from(value) / 100.0),
+        )])
+    }
+
+    fn volume(&mut self, _target: &str) -> Option<f64> {
+        let percent = self.request(MpdClient::volume).ok()??;
+        Some(f64::from(percent) / 100.0)
+    }
+
+    fn set_volume(&mut self, _target: &str, volume: f64) {
+        let percent = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        if let Err(error) = self.request(|client| client.set_volume(percent)) {
+            log::warn!("Could not set the MPD volume: {}", error);
+        }
+    }
+}
+
+/// A player controlled through shell commands, e.g. cmus through cmus-remote
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandPlayer {
+    pub name: String,
+    pub status: String, // Playing if it exits successfully, or prints playing_output if set
+    pub playing_output: Option<String>,
+    pub paused_output: Option<String>, // Without it the player counts as paused while not playing
+    pub pause: String,
+    pub resume: String,
+}
+
+// Runs a command line with sh, None if it could not be started
+fn run_shell(command: &str) -> Option<std::process::Output> {
+    match std::process::Command::new("sh")
+        .args(["-c", command])
         .output()
-        .expect("dbus command failed hard!");
-    let status_stdout = String::from_utf8_lossy(&status.stdout);
-    if (*status_stdout).contains("Playing") {
-        return true;
-    }
-    return false;
-}
-
-pub fn spotify_play_pause() -> () {
-    Command::new("dbus-send")
-        .args(&[
-            "--print-reply",
-            "--dest=org.mpris.MediaPlayer2.spotify",
-            "/org/mpris/MediaPlayer2",
-            "org.mpris.MediaPlayer2.Player.PlayPause",
-        ])
-        .spawn()
-        .expect("dbus command failed hard!");
-}
-
-pub fn mpd_playing() -> bool {
-    let status = match Command::new("mpc").args(&["status"]).output() {
-        Ok(value) => value,
-        Err(error) => return false,
+    {
+        Ok(value) => Some(value),
+        Err(error) => {
+            log::warn!("Could not run {:?}: {}", command, error);
+            None
+        }
+    }
+}
+
+impl CommandPlayer {
+    pub fn cmus() -> Self {
+        CommandPlayer {
+            name: "cmus".to_string(),
+            status: "cmus-remote -Q".to_string(),
+            playing_output: Some("status playing".to_string()),
+            paused_output: Some("status paused".to_string()),
+            pause: "cmus-remote -U".to_string(),
+            resume: "cmus-remote -p".to_string(),
+        }
+    }
+
+    /// Parses "name|status command|pause command|resume command"
+    pub fn parse(spec: &str) -> Result<Self, io::Error> {
+        let fields: Vec<&str> = spec.split('|').map(|field| field.trim()).collect();
+        match fields[..] {
+            [name, status, pause, resume] if fields.iter().all(|field| !field.is_empty()) => {
+                Ok(CommandPlayer {
+                    name: name.to_string(),
+                    status: status.to_string(),
+                    playing_output: None,
+                    paused_output: None,
+                    pause: pause.to_string(),
+                    resume: resume.to_string(),
+                })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid player command {}, expected name|status|pause|resume",
+                    spec
+                ),
+            )),
+        }
+    }
+}
+
+impl CommandPlayer {
+    fn is_paused(&self) -> bool {
+        let output = match run_shell(&self.status) {
+            Some(value) => value,
+            None => return false,
+        };
+        match (&self.paused_output, &self.playing_output) {
+            (Some(text), _) => String::from_utf8_lossy(&output.stdout).contains(text.as_str()),
+            (None, Some(text)) => !String::from_utf8_lossy(&output.stdout).contains(text.as_str()),
+            (None, None) => !output.status.success(),
+        }
+    }
+}
+
+impl ExternalPlayer for CommandPlayer {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn is_playing(&mut self) -> bool {
+        let output = match run_shell(&self.status) {
+            Some(value) => value,
+            None => return false,
+        };
+        match &self.playing_output {
+            Some(text) => String::from_utf8_lossy(&output.stdout).contains(text.as_str()),
+            None => output.status.success(),
+        }
+    }
+
+    fn pause_playing(&mut self) -> Vec<String> {
+        if !self.is_playing() {
+            return vec![];
+        }
+        run_shell(&self.pause);
+        vec![self.name()]
+    }
+
+    fn resume(&mut self, _paused: &[String]) {
+        if self.is_paused() {
+            run_shell(&self.resume);
+        } else {
+            log::info!("Not resuming {}, it is no longer paused", self.name);
+        }
+    }
+}
+
+/// Players that can be picked by name on the command line
+pub const PLAYER_NAMES: [&str; 3] = ["mpris", "mpd", "cmus"];
+
+/// Settings of one external player, the registry creates the players from these
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerConfig {
+    Mpris(PlayerFilter),
+    Mpd(MpdConfig),
+    Command(CommandPlayer),
+}
+
+impl PlayerConfig {
+    /// MPRIS players and mpd, what the daemon pauses unless configured otherwise
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            PlayerConfig::Mpris(PlayerFilter::default()),
+            PlayerConfig::Mpd(MpdConfig::default()),
+        ]
+    }
+
+    /// The player with the name from PLAYER_NAMES, MPRIS players are limited by the filter
+    pub fn from_name(name: &str, filter: &PlayerFilter) -> Result<Self, io::Error> {
+        match name {
+            "mpris" => Ok(PlayerConfig::Mpris(filter.clone())),
+            "mpd" => Ok(PlayerConfig::Mpd(MpdConfig::from_env())),
+            "cmus" => Ok(PlayerConfig::Command(CommandPlayer::cmus())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown player {}, expected one of {}",
+                    name,
+                    PLAYER_NAMES.join(", ")
+                ),
+            )),
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn ExternalPlayer> {
+        match self {
+            PlayerConfig::Mpris(filter) => Box::new(MprisPlayers::new(filter.clone())),
+            PlayerConfig::Mpd(config) => Box::new(MpdPlayer::new(config.clone())),
+            PlayerConfig::Command(player) => Box::new(player.clone()),
+        }
+    }
+}
+
+/// Remembers the players the daemon paused, so a restarted daemon can still resume them
+pub const PAUSED_PLAYERS_FILE_PATH: &str = "/tmp/smqueue.paused";
+
+/// How long the volume of ducked players takes to go down or back up
+pub const DUCK_RAMP: Duration = Duration::from_millis(600);
+const DUCK_STEPS: u32 = 12;
+// A ducked volume that moved further than this was changed by the user and is not restored
+const VOLUME_TOLERANCE: f64 = 0.02;
+
+/// Lowering the volume of external players instead of pausing them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ducking {
+    pub amount: f64, // Part of the volume taken away, 0.7 leaves 30%
+    pub ramp: Duration,
+}
+
+impl Ducking {
+    pub fn new(amount: f64) -> Self {
+        Ducking {
+            amount: amount.clamp(0.0, 1.0),
+            ramp: DUCK_RAMP,
+        }
+    }
+}
+
+/// Volume of something the registry ducked
+#[derive(Debug, Clone, PartialEq)]
+struct Ducked {
+    target: String,
+    original: f64,
+    lowered: f64,
+}
+
+// A volume moving in steps over the ramp duration, without blocking the caller
+#[derive(Debug, Clone, PartialEq)]
+struct Ramp {
+    player: String,
+    target: String,
+    from: f64,
+    to: f64,
+    duration: Duration,
+    started: Instant,
+    step: u32, // Steps applied so far
+}
+
+impl Ramp {
+    // Volume of the step that is due now, None if it was applied already
+    fn due(&mut self) -> Option<f64> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let step = match self.duration.is_zero() {
+            true => DUCK_STEPS,
+            false => (elapsed / self.duration.as_secs_f64() * f64::from(DUCK_STEPS)) as u32 + 1,
+        }
+        .min(DUCK_STEPS);
+        if step <= self.step {
+            return None;
+        }
+        self.step = step;
+        let progress = f64::from(step) / f64::from(DUCK_STEPS);
+        Some(self.from + (self.to - self.from) * progress)
+    }
+
+    fn is_done(&self) -> bool {
+        self.step >= DUCK_STEPS
+    }
+}
+
+// Replaces the ramp the target may still have, the first step is applied right away
+fn start_ramp(
+    ramps: &mut Vec<Ramp>,
+    player: &mut dyn ExternalPlayer,
+    target: &str,
+    from: f64,
+    to: f64,
+    duration: Duration,
+) {
+    let name = player.name();
+    ramps.retain(|ramp| ramp.player != name || ramp.target != target);
+    let mut ramp = Ramp {
+        player: name,
+        target: target.to_string(),
+        from,
+        to,
+        duration,
+        started: Instant::now(),
+        step: 0,
     };
-    let status_stdout = String::from_utf8_lossy(&status.stdout);
-    if (*status_stdout).contains("playing") {
-        return true;
+    if let Some(volume) = ramp.due() {
+        player.set_volume(target, volume);
+    }
+    if !ramp.is_done() {
+        ramps.push(ramp);
     }
-    return false;
 }
 
-pub fn mpd_play() -> () {
-    Command::new("mpc").args(&["play"]).spawn().ok();
+/// The external players the daemon pauses or ducks when it starts playing.
+/// Only the players it paused or ducked are restored once it is done.
+#[derive(Debug)]
+pub struct PlayerRegistry {
+    players: Vec<Box<dyn ExternalPlayer>>,
+    paused: BTreeMap<String, Vec<String>>, // Player name to what pause_playing returned
+    ducked: BTreeMap<String, Vec<Ducked>>, // Player name to the volumes it had
+    ducking: Option<Ducking>,
+    ramps: Vec<Ramp>, // Volumes still moving, advanced by tick
+    state_file: Option<PathBuf>,
 }
 
-pub fn mpd_pause() -> () {
-    Command::new("mpc").args(&["pause"]).spawn().ok();
+impl PlayerRegistry {
+    pub fn new(players: Vec<Box<dyn ExternalPlayer>>) -> Self {
+        PlayerRegistry {
+            players,
+            paused: BTreeMap::new(),
+            ducked: BTreeMap::new(),
+            ducking: None,
+            ramps: vec![],
+            state_file: None,
+        }
+    }
+
+    pub fn from_config(configs: &[PlayerConfig]) -> Self {
+        PlayerRegistry::new(configs.iter().map(|config| config.create()).collect())
+    }
+
+    /// Ducks the players that have a volume instead of pausing them
+    pub fn with_ducking(mut self, ducking: Option<Ducking>) -> Self {
+        self.ducking = ducking;
+        self
+    }
+
+    /// Keeps the paused players in the file, starting with the ones a previous daemon left there
+    pub fn with_state_file(mut self, path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                for line in content.lines() {
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    match fields[..] {
+                        [player, target] => self
+                            .paused
+                            .entry(player.to_string())
+                            .or_default()
+                            .push(target.to_string()),
+                        [player, target, original, lowered] => {
+                            if let (Ok(original), Ok(lowered)) = (original.parse(), lowered.parse())
+                            {
+                                self.ducked
+                                    .entry(player.to_string())
+                                    .or_default()
+                                    .push(Ducked {
+                                        target: target.to_string(),
+                                        original,
+                                        lowered,
+                                    });
+                            }
+                        }
+                        _ => log::warn!("Invalid paused player {:?}", line),
+                    }
+                }
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => log::warn!("Could not read paused players: {}", error),
+        }
+        self.state_file = Some(path.to_path_buf());
+        self
+    }
+
+    fn save(&mut self) {
+        self.ducked.retain(|_, ducked| !ducked.is_empty());
+        let path = match &self.state_file {
+            Some(value) => value,
+            None => return,
+        };
+        let result = if self.paused.is_empty() && self.ducked.is_empty() {
+            fs::remove_file(path).or_else(|error| match error.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(error),
+            })
+        } else {
+            let paused = self.paused.iter().flat_map(|(player, paused)| {
+                paused
+                    .iter()
+                    .map(move |value| format!("{}\t{}\n", player, value))
+            });
+            let ducked = self.ducked.iter().flat_map(|(player, ducked)| {
+                ducked.iter().map(move |value| {
+                    format!(
+                        "{}\t{}\t{}\t{}\n",
+                        player, value.target, value.original, value.lowered
+                    )
+                })
+            });
+            fs::write(path, paused.chain(ducked).collect::<String>())
+        };
+        if let Err(error) = result {
+            log::warn!("Could not save paused players: {}", error);
+        }
+    }
+
+    /// Pauses or ducks the players that are playing, players paused earlier stay paused
+    pub fn pause_playing(&mut self) {
+        for player in self.players.iter_mut() {
+            let volumes = self
+                .ducking
+                .and_then(|ducking| Some((ducking, player.playing_volumes()?)));
+            let paused = match volumes {
+                Some((ducking, volumes)) => {
+                    let ducked = self.ducked.entry(player.name()).or_default();
+                    let mut without_volume = vec![];
+                    for (target, volume) in volumes {
+                        let volume = match volume {
+                            Some(value) => value,
+                            None => {
+                                without_volume.push(target);
+                                continue;
+                            }
+                        };
+                        if ducked.iter().any(|value| value.target == target) {
+                            continue;
+                        }
+                        log::info!("Ducking external player {}", target);
+                        // A volume still being restored goes back to where the restore was headed
+                        let original = self
+                            .ramps
+                            .iter()
+                            .find(|ramp| ramp.player == player.name() && ramp.target == target)
+                            .map_or(volume, |ramp| ramp.to);
+                        let lowered = original * (1.0 - ducking.amount);
+                        start_ramp(
+                            &mut self.ramps,
+                            player.as_mut(),
+                            &target,
+                            volume,
+                            lowered,
+                            ducking.ramp,
+                        );
+                        ducked.push(Ducked {
+                            target,
+                            original,
+                            lowered,
+                        });
+                    }
+                    // What can not be ducked is paused like without ducking
+                    match without_volume.is_empty() {
+                        true => vec![],
+                        false => player.pause_targets(&without_volume),
+                    }
+                }
+                None => player.pause_playing(),
+            };
+            if paused.is_empty() {
+                continue;
+            }
+            log::info!("Paused external player {}", player.name());
+            let entry = self.paused.entry(player.name()).or_default();
+            for value in paused {
+                if !entry.contains(&value) {
+                    entry.push(value);
+                }
+            }
+        }
+        self.save();
+    }
+
+    /// Resumes the players paused by pause_playing that are still paused
+    /// and brings the ducked ones back to their volume
+    pub fn resume_paused(&mut self) {
+        let mut paused = std::mem::take(&mut self.paused);
+        let mut ducked = std::mem::take(&mut self.ducked);
+        let ramp = self.ducking.map_or(DUCK_RAMP, |ducking| ducking.ramp);
+        for player in self.players.iter_mut() {
+            if let Some(values) = paused.remove(&player.name()) {
+                log::info!("Resuming external player {}", player.name());
+                player.resume(&values);
+            }
+            for value in ducked.remove(&player.name()).unwrap_or_default() {
+                // A volume still being lowered is ours, not changed by the user
+                let lowering = self
+                    .ramps
+                    .iter()
+                    .any(|ramp| ramp.player == player.name() && ramp.target == value.target);
+                match player.volume(&value.target) {
+                    Some(volume)
+                        if lowering || (volume - value.lowered).abs() <= VOLUME_TOLERANCE =>
+                    {
+                        log::info!("Restoring the volume of {}", value.target);
+                        start_ramp(
+                            &mut self.ramps,
+                            player.as_mut(),
+                            &value.target,
+                            volume,
+                            value.original,
+                            ramp,
+                        );
+                    }
+                    volume => log::info!(
+                        "Not restoring the volume of {}, it is {:?} now",
+                        value.target,
+                        volume
+                    ),
+                }
+            }
+        }
+        for player in paused.keys().chain(ducked.keys()) {
+            log::warn!("Player {} is no longer configured, not resuming it", player);
+        }
+        self.save();
+    }
+
+    /// Applies the volume steps that are due, the mpv thread calls this between its events
+    pub fn tick(&mut self) {
+        let players = &mut self.players;
+        self.ramps.retain_mut(|ramp| {
+            let player = match players
+                .iter_mut()
+                .find(|player| player.name() == ramp.player)
+            {
+                Some(value) => value,
+                None => return false,
+            };
+            if let Some(volume) = ramp.due() {
+                player.set_volume(&ramp.target, volume);
+            }
+            !ramp.is_done()
+        });
+    }
+
+    /// Jumps to the end of every ramp, e.g. when the daemon exits
+    pub fn finish_ramps(&mut self) {
+        for ramp in std::mem::take(&mut self.ramps) {
+            if let Some(player) = self
+                .players
+                .iter_mut()
+                .find(|player| player.name() == ramp.player)
+            {
+                player.set_volume(&ramp.target, ramp.to);
+            }
+        }
+    }
+
+    pub fn paused_names(&self) -> Vec<String> {
+        self.paused.values().flatten().cloned().collect()
+    }
+
+    pub fn ducked_names(&self) -> Vec<String> {
+        self.ducked
+            .values()
+            .flatten()
+            .map(|value| value.target.clone())
+            .collect()
+    }
 }
 
-pub fn mpd_play_pause() -> () {
-    if mpd_playing() {
-        mpd_pause();
-    } else {
-        mpd_play();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::Variant;
+    use dbus::channel::{Channel, MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use std::net::TcpListener;
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_player_filter() {
+        let filter = PlayerFilter {
+            allow: vec![],
+            deny: vec!["firefox".to_string()],
+        };
+        assert!(filter.allows("org.mpris.MediaPlayer2.spotify"));
+        assert!(!filter.allows("org.mpris.MediaPlayer2.firefox.instance1234"));
+        assert!(filter.allows("org.mpris.MediaPlayer2.firefoxish"));
+        let filter = PlayerFilter {
+            allow: vec!["vlc".to_string(), "rhythmbox".to_string()],
+            deny: vec!["rhythmbox".to_string()],
+        };
+        assert!(filter.allows("org.mpris.MediaPlayer2.vlc"));
+        assert!(!filter.allows("org.mpris.MediaPlayer2.rhythmbox"));
+        assert!(!filter.allows("org.mpris.MediaPlayer2.spotify"));
+    }
+
+    // Starts a dbus-daemon of its own, None if it is not installed
+    fn private_bus() -> Option<(std::process::Child, String)> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        std::io::BufRead::read_line(
+            &mut std::io::BufReader::new(daemon.stdout.as_mut().unwrap()),
+            &mut address,
+        )
+        .ok()?;
+        Some((daemon, address.trim().to_string()))
+    }
+
+    // Serves the MPRIS player interface under the bus name and records Play and Pause calls.
+    // Volume changes are recorded as "Volume <volume>".
+    // Players without a volume answer reading it with an error, like a missing property
+    fn fake_player(
+        address: &str,
+        bus_name: &str,
+        status: &str,
+        volume: Option<f64>,
+    ) -> Arc<Mutex<Vec<String>>> {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        let connection = Connection::from(channel);
+        connection
+            .request_name(bus_name, false, true, false)
+            .unwrap();
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let mut status = status.to_string();
+        let mut volume = volume;
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                let member = message.member().unwrap().to_string();
+                let reply = match member.as_str() {
+                    "Get" => match message.read2::<String, String>() {
+                        Ok((_, property)) if property == "Volume" => match volume {
+                            Some(value) => message.method_return().append1(Variant(value)),
+                            None => message.error(
+                                &"org.freedesktop.DBus.Error.InvalidArgs".into(),
+                                &std::ffi::CString::new("No such property").unwrap(),
+                            ),
+                        },
+                        _ => message.method_return().append1(Variant(status.clone())),
+                    },
+                    "Set" => {
+                        let (_, _, Variant(value)) =
+                            message.read3::<String, String, Variant<f64>>().unwrap();
+                        volume = Some(value);
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push(format!("Volume {:.2}", value));
+                        message.method_return()
+                    }
+                    "Pause" | "Play" => {
+                        status = if member == "Play" {
+                            "Playing"
+                        } else {
+                            "Paused"
+                        }
+                        .to_string();
+                        recorded.lock().unwrap().push(member);
+                        message.method_return()
+                    }
+                    _ => message.method_return(),
+                };
+                let _ = connection.send(reply);
+                true
+            }),
+        );
+        std::thread::spawn(move || while connection.process(Duration::from_secs(1)).is_ok() {});
+        calls
+    }
+
+    #[test]
+    fn test_pause_and_resume_players_on_private_bus() {
+        let (mut daemon, address) = match private_bus() {
+            Some(value) => value,
+            None => {
+                println!("dbus-daemon is not installed, skipping");
+                return;
+            }
+        };
+        let vlc = fake_player(&address, "org.mpris.MediaPlayer2.vlc", "Playing", Some(1.0));
+        let spotify = fake_player(
+            &address,
+            "org.mpris.MediaPlayer2.spotify",
+            "Paused",
+            Some(1.0),
+        );
+        let firefox = fake_player(
+            &address,
+            "org.mpris.MediaPlayer2.firefox.instance5",
+            "Playing",
+            Some(1.0),
+        );
+
+        let mpris = Mpris::with_address(&address).unwrap();
+        let mut players = mpris.players().unwrap();
+        players.sort();
+        assert_eq!(
+            players,
+            vec![
+                "org.mpris.MediaPlayer2.firefox.instance5",
+                "org.mpris.MediaPlayer2.spotify",
+                "org.mpris.MediaPlayer2.vlc"
+            ]
+        );
+        assert_eq!(
+            mpris
+                .playback_status("org.mpris.MediaPlayer2.spotify")
+                .unwrap(),
+            PlaybackStatus::Paused
+        );
+        // Players that went away are an error, not a hang
+        assert!(mpris
+            .playback_status("org.mpris.MediaPlayer2.gone")
+            .is_err());
+
+        let filter = PlayerFilter {
+            allow: vec![],
+            deny: vec!["firefox".to_string()],
+        };
+        let mut external = MprisPlayers::with_connection(filter, mpris);
+        let paused = external.pause_playing();
+        assert_eq!(paused, ["org.mpris.MediaPlayer2.vlc"]);
+        // A second start does not pause anything again
+        assert!(external.pause_playing().is_empty());
+        external.resume(&paused);
+        // Resuming what is already playing is skipped
+        external.resume(&paused);
+
+        assert_eq!(*vlc.lock().unwrap(), vec!["Pause", "Play"]);
+        assert!(spotify.lock().unwrap().is_empty());
+        assert!(firefox.lock().unwrap().is_empty());
+
+        // Ducking lowers the volume in steps and restores it afterwards,
+        // players without a volume are paused instead
+        let mopidy = fake_player(&address, "org.mpris.MediaPlayer2.mopidy", "Playing", None);
+        let filter = PlayerFilter {
+            allow: vec!["vlc".to_string(), "mopidy".to_string()],
+            deny: vec![],
+        };
+        let external =
+            MprisPlayers::with_connection(filter, Mpris::with_address(&address).unwrap());
+        let mut registry =
+            PlayerRegistry::new(vec![Box::new(external)]).with_ducking(Some(Ducking {
+                amount: 0.7,
+                ramp: Duration::ZERO,
+            }));
+        vlc.lock().unwrap().clear();
+        registry.pause_playing();
+        registry.pause_playing();
+        assert_eq!(registry.ducked_names(), ["org.mpris.MediaPlayer2.vlc"]);
+        assert_eq!(registry.paused_names(), ["org.mpris.MediaPlayer2.mopidy"]);
+        // Without a ramp the volume is set at once
+        assert_eq!(*vlc.lock().unwrap(), vec!["Volume 0.30"]);
+        assert_eq!(*mopidy.lock().unwrap(), vec!["Pause"]);
+        registry.resume_paused();
+        assert!(registry.ducked_names().is_empty());
+        assert!(registry.paused_names().is_empty());
+        assert_eq!(vlc.lock().unwrap().last().unwrap(), "Volume 1.00");
+        assert_eq!(*mopidy.lock().unwrap(), vec!["Pause", "Play"]);
+
+        // A volume the user changed in between is kept
+        registry.pause_playing();
+        let user = Mpris::with_address(&address).unwrap();
+        user.set_volume("org.mpris.MediaPlayer2.vlc", 0.5).unwrap();
+        registry.resume_paused();
+        assert_eq!(vlc.lock().unwrap().last().unwrap(), "Volume 0.50");
+
+        // Ramps only take a step per tick, the caller is not blocked
+        registry.ducking = Some(Ducking {
+            amount: 0.5,
+            ramp: Duration::from_millis(120),
+        });
+        vlc.lock().unwrap().clear();
+        registry.pause_playing();
+        assert_eq!(*vlc.lock().unwrap(), vec!["Volume 0.48"]);
+        while !registry.ramps.is_empty() {
+            std::thread::sleep(Duration::from_millis(5));
+            registry.tick();
+        }
+        let calls = vlc.lock().unwrap().clone();
+        assert!(calls.len() > 1 && calls.len() <= DUCK_STEPS as usize);
+        assert_eq!(calls.last().unwrap(), "Volume 0.25");
+        // Ducking while the volume is restored keeps the original one,
+        // resuming while it is lowered still restores it
+        registry.resume_paused();
+        registry.pause_playing();
+        registry.resume_paused();
+        registry.finish_ramps();
+        assert_eq!(vlc.lock().unwrap().last().unwrap(), "Volume 0.50");
+        assert!(registry.ducked_names().is_empty());
+        daemon.kill().unwrap();
+        daemon.wait().unwrap();
+    }
+
+    // Answers one connection like MPD would and records the commands it got
+    fn fake_mpd(password: &'static str) -> (MpdConfig, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let commands = Arc::new(Mutex::new(vec![]));
+        let recorded = commands.clone();
+        std::thread::spawn(move || {
+            let mut state = "play";
+            let mut volume = "40".to_string();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"OK MPD 0.23.5\n").unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let command = line.trim().to_string();
+                    line.clear();
+                    recorded.lock().unwrap().push(command.clone());
+                    let reply = match command.as_str() {
+                        "status" => {
+                            format!("volume: {}\nrepeat: 0\nstate: {}\nOK\n", volume, state)
+                        }
+                        _ if command.starts_with("setvol ") => {
+                            volume = command["setvol ".len()..].to_string();
+                            "OK\n".to_string()
+                        }
+                        "pause 1" => {
+                            state = "pause";
+                            "OK\n".to_string()
+                        }
+                        "play" => {
+                            state = "play";
+                            "OK\n".to_string()
+                        }
+                        _ if command == format!("password {}", mpd_quote(password)) => {
+                            "OK\n".to_string()
+                        }
+                        _ => "ACK [3@0] {password} incorrect password\n".to_string(),
+                    };
+                    stream.write_all(reply.as_bytes()).unwrap();
+                }
+            }
+        });
+        let mut config = MpdConfig::parse(Some(&format!("{}@127.0.0.1", password)), Some(&port));
+        config.timeout = Duration::from_secs(2);
+        (config, commands)
+    }
+
+    #[test]
+    fn test_mpd_client_against_fake_server() {
+        let (config, commands) = fake_mpd("se\"cret");
+        let mut player = MpdPlayer::new(config.clone());
+        assert!(player.is_playing());
+        assert_eq!(player.pause_playing(), vec!["mpd".to_string()]);
+        assert!(!player.is_playing());
+        player.resume(&[]);
+        assert!(player.is_playing());
+        assert_eq!(player.volume("mpd"), Some(0.4));
+        player.set_volume("mpd", 0.12);
+        assert_eq!(player.volume("mpd"), Some(0.12));
+        // One connection serves every call, the fake server answers one at a time
+        drop(player);
+        assert_eq!(
+            commands.lock().unwrap()[..4],
+            ["password \"se\\\"cret\"", "status", "status", "pause 1"]
+        );
+        let passwords = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|command| command.starts_with("password"))
+            .count();
+        assert_eq!(passwords, 1);
+
+        let mut client = MpdClient::connect(&config).unwrap();
+        assert_eq!(client.state().unwrap(), MpdState::Play);
+        client.pause().unwrap();
+        assert_eq!(client.state().unwrap(), MpdState::Pause);
+        assert_eq!(client.volume().unwrap(), Some(12));
+        drop(client);
+
+        let mut wrong = config.clone();
+        wrong.password = Some("guess".to_string());
+        let error = MpdClient::connect(&wrong).unwrap_err();
+        assert!(error.to_string().contains("incorrect password"));
+        assert!(!MpdPlayer::new(wrong).is_playing());
+    }
+
+    #[test]
+    fn test_mpd_config_from_host_and_port() {
+        assert_eq!(
+            MpdConfig::parse(None, None).address,
+            MpdAddress::Tcp("localhost".to_string(), DEFAULT_MPD_PORT)
+        );
+        let config = MpdConfig::parse(Some("secret@music.local"), Some("6700"));
+        assert_eq!(config.password, Some("secret".to_string()));
+        assert_eq!(
+            config.address,
+            MpdAddress::Tcp("music.local".to_string(), 6700)
+        );
+        let config = MpdConfig::parse(Some("/run/mpd/socket"), Some("6700"));
+        assert_eq!(config.password, None);
+        assert_eq!(
+            config.address,
+            MpdAddress::Socket(PathBuf::from("/run/mpd/socket"))
+        );
+    }
+
+    #[test]
+    fn test_registry_resumes_only_paused_players() {
+        let directory =
+            std::env::temp_dir().join(format!("smqueue-players-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        // Each player is playing while its marker file exists
+        let player = |name: &str| {
+            let marker = directory.join(name).to_str().unwrap().to_string();
+            CommandPlayer::parse(&format!(
+                "{0}|test -f {1}|rm {1}; echo {0} >> {2}/log|touch {1}; echo {0} >> {2}/log",
+                name,
+                marker,
+                directory.to_str().unwrap()
+            ))
+            .unwrap()
+        };
+        std::fs::write(directory.join("cmus"), "").unwrap();
+        let configs = [
+            PlayerConfig::Command(player("cmus")),
+            PlayerConfig::Command(player("script")),
+        ];
+        let state_file = directory.join("paused");
+        let mut registry = PlayerRegistry::from_config(&configs).with_state_file(&state_file);
+        registry.pause_playing();
+        assert_eq!(registry.paused_names(), vec!["cmus"]);
+        assert!(!directory.join("cmus").exists());
+
+        // A player started in between is paused as well, the others are left alone
+        std::fs::write(directory.join("script"), "").unwrap();
+        registry.pause_playing();
+        assert_eq!(registry.paused_names(), vec!["cmus", "script"]);
+
+        // A restarted daemon still knows what it paused
+        drop(registry);
+        let mut registry = PlayerRegistry::from_config(&configs).with_state_file(&state_file);
+        assert_eq!(registry.paused_names(), vec!["cmus", "script"]);
+
+        // The user started the script player again, so it is not resumed a second time
+        std::fs::write(directory.join("script"), "").unwrap();
+        registry.resume_paused();
+        assert!(registry.paused_names().is_empty());
+        assert!(!state_file.exists());
+        assert!(directory.join("cmus").exists());
+        assert_eq!(
+            std::fs::read_to_string(directory.join("log")).unwrap(),
+            "cmus\nscript\ncmus\n"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(CommandPlayer::parse("only|two").is_err());
+        assert!(PlayerConfig::from_name("winamp", &PlayerFilter::default()).is_err());
+        assert_eq!(
+            PlayerConfig::from_name("cmus", &PlayerFilter::default()).unwrap(),
+            PlayerConfig::Command(CommandPlayer::cmus())
+        );
     }
 }
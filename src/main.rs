@@ -3,14 +3,17 @@ use std::io;
 #[cfg(target_os = "linux")]
 mod alsa_controller;
 mod daemon;
+mod daemon_cache;
 mod daemon_downloader;
 mod daemon_queue;
 mod external_program_status;
 mod history_watcher;
+mod media_resolver;
 mod socket_com;
 mod status_watcher;
 mod tab_elements;
 mod terminal_ui;
+mod volume_backend;
 
 use log::LevelFilter;
 use log4rs::append::file::FileAppender;
@@ -22,7 +25,15 @@ use dirs::home_dir;
 
 use log::info;
 
-use crate::socket_com::{SocketCom, DEFAULT_PRIORITY};
+#[cfg(target_os = "linux")]
+use crate::alsa_controller::AlsaConfig;
+use crate::external_program_status::{CommandPlayer, Ducking, PlayerConfig, PlayerFilter};
+use crate::socket_com::{
+    parse_playlist_items, parse_schedule_time, FormatOptions, PlaylistOptions, Schedule, SocketCom,
+    DEFAULT_PRIORITY,
+};
+use crate::status_watcher::RepeatMode;
+use crate::volume_backend::{VolumeConfig, VOLUME_BACKEND_NAMES};
 
 fn init_log(log_file_name: &str) -> () {
     let logfile = FileAppender::builder()
@@ -46,6 +57,28 @@ fn init_log(log_file_name: &str) -> () {
     log4rs::init_config(config).unwrap();
 }
 
+// Format options shared by queueing and the daemon defaults
+fn format_options(args: &clap::ArgMatches) -> Result<FormatOptions, io::Error> {
+    let max_height = match args.value_of("max_height") {
+        Some(value) => Some(value.parse::<u32>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid resolution {}", value),
+            )
+        })?),
+        None => None,
+    };
+    Ok(FormatOptions {
+        max_height,
+        codec: args.value_of("codec").map(|value| value.to_string()),
+        audio_only: match (args.is_present("audio_only"), args.is_present("with_video")) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+    })
+}
+
 fn main() -> Result<(), io::Error> {
     let args = App::new("Rustysm")
                 .version("0.1.4")
@@ -64,11 +97,98 @@ fn main() -> Result<(), io::Error> {
                         .long("pause")
                         .takes_value(false)
                         .help("Pauses playing video in showmovie backend"))
+                .arg(Arg::with_name("undo")
+                        .long("undo")
+                        .takes_value(false)
+                        .help("Undoes the last remove, clear or jump in showmovie backend"))
+                .arg(Arg::with_name("repeat")
+                        .long("repeat")
+                        .takes_value(true)
+                        .possible_values(&["off", "one", "queue"])
+                        .help("Sets the repeat mode of showmovie backend"))
+                .arg(Arg::with_name("shuffle")
+                        .long("shuffle")
+                        .takes_value(true)
+                        .possible_values(&["on", "off"])
+                        .help("Shuffles entries with the same priority in showmovie backend"))
+                .arg(Arg::with_name("mute")
+                        .long("mute")
+                        .takes_value(true)
+                        .possible_values(&["on", "off"])
+                        .help("Mutes the output of showmovie backend"))
+                .arg(Arg::with_name("volume")
+                        .long("volume")
+                        .takes_value(true)
+                        .value_name("PERCENT")
+                        .help("Sets the volume of showmovie backend"))
                 .arg(Arg::with_name("daemon")
                         .short("d")
                         .long("daemon")
                         .takes_value(false)
                         .help("Launches rustysm daemon mode to recive commands from client"))
+                .arg(Arg::with_name("download_workers")
+                        .long("download-workers")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("Number of videos the daemon downloads at the same time"))
+                .arg(Arg::with_name("download_dir")
+                        .long("download-dir")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory the daemon downloads upcoming entries to"))
+                .arg(Arg::with_name("prefetch")
+                        .long("prefetch")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("Number of upcoming entries the daemon downloads ahead, 0 streams everything"))
+                .arg(Arg::with_name("download_budget")
+                        .long("download-budget")
+                        .takes_value(true)
+                        .value_name("MB")
+                        .help("Disk space in megabytes the daemon may use for prefetched entries"))
+                .arg(Arg::with_name("cache_size")
+                        .long("cache-size")
+                        .takes_value(true)
+                        .value_name("MB")
+                        .help("Size in megabytes of the daemon's download cache, counts towards the download budget"))
+                .arg(Arg::with_name("download_retries")
+                        .long("download-retries")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("How often the daemon retries a failed download, waiting twice as long each time"))
+                .arg(Arg::with_name("resolver")
+                        .long("resolver")
+                        .takes_value(true)
+                        .value_name("BACKEND")
+                        .possible_values(&media_resolver::RESOLVER_NAMES)
+                        .help("Backend used to identify, expand and download media, auto picks one per url"))
+                .arg(Arg::with_name("players_allow")
+                        .long("players-allow")
+                        .takes_value(true)
+                        .value_name("NAMES")
+                        .help("Comma separated MPRIS players, e.g. spotify,vlc, the daemon may pause. All by default"))
+                .arg(Arg::with_name("players_deny")
+                        .long("players-deny")
+                        .takes_value(true)
+                        .value_name("NAMES")
+                        .help("Comma separated MPRIS players the daemon never pauses"))
+                .arg(Arg::with_name("external_players")
+                        .long("external-players")
+                        .takes_value(true)
+                        .value_name("NAMES")
+                        .help("Comma separated players the daemon pauses while playing: mpris, mpd and cmus. Defaults to mpris,mpd"))
+                .arg(Arg::with_name("player_command")
+                        .long("player-command")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("NAME|STATUS|PAUSE|RESUME")
+                        .help("Pauses another player through shell commands, it counts as playing when STATUS exits successfully"))
+                .arg(Arg::with_name("duck")
+                        .long("duck")
+                        .takes_value(true)
+                        .value_name("PERCENT")
+                        .help("Lowers the volume of external players by PERCENT while playing instead of pausing them. Players without a volume are still paused"))
                 .arg(Arg::with_name("tickrate")
                         .short("t")
                         .long("tickrate")
@@ -84,11 +204,93 @@ fn main() -> Result<(), io::Error> {
                         .long("history_file")
                         .takes_value(true)
                         .help("Provides the path to the history file."))
+                .arg(Arg::with_name("volume_backend")
+                        .long("volume-backend")
+                        .takes_value(true)
+                        .possible_values(&VOLUME_BACKEND_NAMES)
+                        .help("Volume control of the volume bar. Auto uses PulseAudio or PipeWire through pactl if it runs, otherwise alsa"))
+                .arg(Arg::with_name("volume_keys")
+                        .long("volume-keys")
+                        .takes_value(true)
+                        .possible_values(&["system", "mpv"])
+                        .help("What +/- and m change in the gui. With mpv the system volume is changed with Alt"))
+                .arg(Arg::with_name("pulse_sink")
+                        .long("pulse-sink")
+                        .takes_value(true)
+                        .value_name("SINK")
+                        .help("PulseAudio or PipeWire sink of the volume bar. Defaults to the default sink"))
+                .arg(Arg::with_name("alsa_card")
+                        .long("alsa-card")
+                        .takes_value(true)
+                        .value_name("CARD")
+                        .help("Alsa card of the volume bar, a number like 1 or a device like hw:1. Defaults to default"))
+                .arg(Arg::with_name("alsa_element")
+                        .long("alsa-element")
+                        .takes_value(true)
+                        .value_name("NAME[,INDEX]")
+                        .help("Mixer element of the volume bar, e.g. PCM. Defaults to Master"))
+                .arg(Arg::with_name("alsa_channel")
+                        .long("alsa-channel")
+                        .takes_value(true)
+                        .value_name("CHANNEL")
+                        .help("Channel the volume is read from, e.g. front-left or mono. Defaults to the last channel"))
                 .arg(Arg::with_name("priority")
                         .short("p")
                         .long("priority")
                         .takes_value(true)
                         .help("Set priority of the queued file"))
+                .arg(Arg::with_name("not_before")
+                        .long("not-before")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .conflicts_with("at")
+                        .help("Hold the queued file back until TIME (HH:MM or \"YYYY-MM-DD HH:MM\")"))
+                .arg(Arg::with_name("at")
+                        .long("at")
+                        .takes_value(true)
+                        .value_name("TIME")
+                        .help("Start the queued file at TIME, interrupting what is playing (HH:MM or \"YYYY-MM-DD HH:MM\")"))
+                .arg(Arg::with_name("playlist_items")
+                        .long("playlist-items")
+                        .takes_value(true)
+                        .value_name("RANGE")
+                        .help("Only queue these playlist items, as START-END, START- or N (1-based)"))
+                .arg(Arg::with_name("playlist_max")
+                        .long("playlist-max")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("Queue at most COUNT entries from a playlist, at least 1"))
+                .arg(Arg::with_name("playlist_reverse")
+                        .long("playlist-reverse")
+                        .takes_value(false)
+                        .help("Queue playlist entries in reverse order"))
+                .arg(Arg::with_name("playlist_shuffle")
+                        .long("playlist-shuffle")
+                        .takes_value(false)
+                        .help("Shuffle playlist entries when they are queued"))
+                .arg(Arg::with_name("playlist_lazy")
+                        .long("playlist-lazy")
+                        .takes_value(false)
+                        .help("Queue a playlist as one entry that is expanded when it is reached"))
+                .arg(Arg::with_name("max_height")
+                        .long("max-height")
+                        .takes_value(true)
+                        .value_name("PIXELS")
+                        .help("Highest video resolution to fetch, e.g. 720. With -d it is the default for all entries"))
+                .arg(Arg::with_name("codec")
+                        .long("codec")
+                        .takes_value(true)
+                        .value_name("CODEC")
+                        .help("Preferred codec, e.g. avc1 or vp9, or opus with --audio-only. With -d it is the default for all entries"))
+                .arg(Arg::with_name("audio_only")
+                        .long("audio-only")
+                        .takes_value(false)
+                        .help("Only fetch the audio of the entry. With -d it is the default for all entries"))
+                .arg(Arg::with_name("with_video")
+                        .long("with-video")
+                        .takes_value(false)
+                        .conflicts_with("audio_only")
+                        .help("Fetch the video of the entry even if the daemon defaults to --audio-only"))
                 .arg(Arg::with_name("raw")
                         .short("r")
                         .long("raw")
@@ -121,36 +323,103 @@ fn main() -> Result<(), io::Error> {
         if args.is_present("history_file") {
             history_file_path = args.value_of("history_file").unwrap().to_string();
         }
+        let mut volume_config = VolumeConfig::default();
+        if let Some(value) = args.value_of("volume_backend") {
+            volume_config.backend = value.to_string();
+        }
+        if let Some(value) = args.value_of("pulse_sink") {
+            volume_config.pulse_sink = value.to_string();
+        }
+        volume_config.mpv_keys = args.value_of("volume_keys") == Some("mpv");
+        #[cfg(target_os = "linux")]
+        {
+            let alsa_config = &mut volume_config.alsa;
+            if let Some(value) = args.value_of("alsa_card") {
+                alsa_config.card = AlsaConfig::card_device(value);
+            }
+            if let Some(value) = args.value_of("alsa_element") {
+                let (element, index) = AlsaConfig::parse_element(value)?;
+                alsa_config.element = element;
+                alsa_config.index = index;
+            }
+            if let Some(value) = args.value_of("alsa_channel") {
+                alsa_config.channel = AlsaConfig::parse_channel(value)?;
+            }
+        }
         info!("Opening with history path {}", history_file_path);
-        let mut ui = terminal_ui::TerminalUi::new(history_file_path)?;
+        let mut ui = terminal_ui::TerminalUi::new(history_file_path, volume_config)?;
         ui.start_draw(tickrate).unwrap();
     } else if args.is_present("daemon") {
         log::info!("Attempting to start daemon");
-        let daemon = daemon::Daemon::new().unwrap();
-        daemon.mpv_play_file("https://www.youtube.com/watch?v=138ajKRMzIY");
-        daemon.mpv_disable_audio_pitch_correction();
-        let mut speed = 0.5;
-        let mut forward = true;
-        loop {
-            std::thread::sleep_ms(100);
-            if forward {
-                speed = speed + 0.01;
-            } else {
-                speed = speed - 0.01;
-            }
-            if speed >= 2.0 {
-                forward = false;
-                daemon.mpv_enable_audio_pitch_correction();
-            }
-            if speed <= 0.5 {
-                forward = true;
-                daemon.mpv_disable_audio_pitch_correction();
-                speed = 0.5;
+        // Numeric daemon options, reported as invalid input instead of panicking
+        let parse_argument = |value: &str| {
+            value.parse::<u64>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid number {}", value),
+                )
+            })
+        };
+        let mut config = daemon::DaemonConfig::default();
+        if let Some(value) = args.value_of("download_workers") {
+            config.download_workers = parse_argument(value)? as usize;
+        }
+        if let Some(value) = args.value_of("download_dir") {
+            config.download_directory = value.to_string();
+        }
+        if let Some(value) = args.value_of("prefetch") {
+            config.prefetch_count = parse_argument(value)? as usize;
+        }
+        if let Some(value) = args.value_of("download_budget") {
+            config.download_budget = parse_argument(value)? * 1024 * 1024;
+        }
+        if let Some(value) = args.value_of("cache_size") {
+            config.cache_size = parse_argument(value)? * 1024 * 1024;
+        }
+        if let Some(value) = args.value_of("download_retries") {
+            config.retry.retries = parse_argument(value)? as u32;
+        }
+        if let Some(value) = args.value_of("resolver") {
+            config.resolver = media_resolver::resolver_by_name(value)?;
+        }
+        let player_names = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        };
+        let mut filter = PlayerFilter::default();
+        if let Some(value) = args.value_of("players_allow") {
+            filter.allow = player_names(value);
+        }
+        if let Some(value) = args.value_of("players_deny") {
+            filter.deny = player_names(value);
+        }
+        config.external_players = vec![];
+        for name in player_names(args.value_of("external_players").unwrap_or("mpris,mpd")) {
+            config
+                .external_players
+                .push(PlayerConfig::from_name(&name, &filter)?);
+        }
+        for spec in args.values_of("player_command").into_iter().flatten() {
+            config
+                .external_players
+                .push(PlayerConfig::Command(CommandPlayer::parse(spec)?));
+        }
+        if let Some(value) = args.value_of("duck") {
+            let percent = parse_argument(value)?;
+            if percent > 100 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid duck percentage {}", value),
+                ));
             }
-            println!("{:?} {:?}", speed, forward);
-
-            daemon.mpv_set_speed(speed);
+            config.ducking = Some(Ducking::new(percent as f64 / 100.0));
         }
+        config.format = format_options(&args)?;
+        let mut daemon = daemon::Daemon::new(config)?;
+        daemon.run()?;
     } else if args.is_present("play") {
         log::info!("Resuming sm backend");
         let mut socket_controller = SocketCom::new().unwrap();
@@ -159,15 +428,81 @@ fn main() -> Result<(), io::Error> {
         log::info!("Pausing sm backend");
         let mut socket_controller = SocketCom::new().unwrap();
         socket_controller.pause_playback();
+    } else if args.is_present("undo") {
+        log::info!("Undoing last queue change");
+        let mut socket_controller = SocketCom::new().unwrap();
+        socket_controller.undo()?;
+    } else if args.is_present("repeat")
+        || args.is_present("shuffle")
+        || args.is_present("mute")
+        || args.is_present("volume")
+    {
+        let mut socket_controller = SocketCom::new().unwrap();
+        if let Some(value) = args.value_of("repeat") {
+            let mode = match value {
+                "one" => RepeatMode::One,
+                "queue" => RepeatMode::Queue,
+                _ => RepeatMode::Off,
+            };
+            socket_controller.set_repeat_mode(mode)?;
+        }
+        if let Some(value) = args.value_of("shuffle") {
+            socket_controller.set_shuffle(value == "on")?;
+        }
+        if let Some(value) = args.value_of("mute") {
+            socket_controller.set_mute(value == "on")?;
+        }
+        if let Some(value) = args.value_of("volume") {
+            let volume = value.parse::<u64>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid volume {}", value),
+                )
+            })?;
+            socket_controller.set_volume(volume)?;
+        }
     } else if args.is_present("QueueFile") {
         let tbq = args.value_of("QueueFile").unwrap();
         let mut priority = DEFAULT_PRIORITY;
         if args.is_present("priority") {
             priority = args.value_of("priority").unwrap().parse::<u64>().unwrap();
         }
+        let mut schedule = None;
+        if args.is_present("not_before") {
+            schedule = Some(Schedule::NotBefore(parse_schedule_time(
+                args.value_of("not_before").unwrap(),
+            )?));
+        } else if args.is_present("at") {
+            schedule = Some(Schedule::StartAt(parse_schedule_time(
+                args.value_of("at").unwrap(),
+            )?));
+        }
+        let mut playlist = PlaylistOptions::default();
+        if args.is_present("playlist_items") {
+            playlist.items = Some(parse_playlist_items(
+                args.value_of("playlist_items").unwrap(),
+            )?);
+        }
+        if let Some(value) = args.value_of("playlist_max") {
+            // 0 means no maximum on the wire, so it is not a valid count here
+            let max_count = value.parse::<u32>().ok().filter(|count| *count > 0);
+            playlist.max_count = Some(max_count.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid playlist maximum {}", value),
+                )
+            })?);
+        }
+        playlist.reverse = args.is_present("playlist_reverse");
+        playlist.shuffle = args.is_present("playlist_shuffle");
+        playlist.lazy = args.is_present("playlist_lazy");
+        let format = format_options(&args)?;
         let mut socket_controller = SocketCom::new().unwrap();
+        if let Some(value) = args.value_of("resolver") {
+            socket_controller.set_resolver(media_resolver::resolver_by_name(value)?);
+        }
         for object_to_be_queued in args.values_of("QueueFile").unwrap().collect::<Vec<_>>() {
-            let result_msg = match socket_controller.add_entry(object_to_be_queued.to_string(), priority, args.is_present("raw")) {
+            let result_msg = match socket_controller.add_entry(object_to_be_queued.to_string(), priority, args.is_present("raw"), schedule, &playlist, &format) {
                 Ok(value) => value,
                 Err(_) => {"Could not successfully queue object.\nConsider using -r to try as filestream for livestreams or other types.".to_string()}
             };
@@ -0,0 +1,744 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::daemon_cache::cache_key;
+use crate::daemon_downloader::{
+    download_http, parse_progress_line, CancelHandle, DownloadProgress,
+};
+use crate::socket_com::FormatOptions;
+
+use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+
+use log::debug;
+
+/// One video or file behind a url
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedMedia {
+    pub id: String,
+    pub extractor: String, // Lowercase, e.g. "youtube", "http" or "local"
+    pub title: Option<String>,
+    pub url: String, // Page url for yt-dlp, the file url or path otherwise
+    pub duration: Option<f64>,
+}
+
+impl ResolvedMedia {
+    /// Builds the "<id or url> - <title>" string youtube entries are queued with
+    pub fn entry_string(&self) -> Option<String> {
+        let title = self.title.clone()?;
+        if self.extractor == "youtube" {
+            return Some(self.id.clone() + " - " + &title);
+        }
+        Some(self.url.clone() + " - " + &title)
+    }
+
+    pub fn cache_key(&self) -> String {
+        cache_key(&self.extractor, &self.id)
+    }
+}
+
+/// What a url resolved to, a single video is a resolution with one entry
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Resolution {
+    pub title: Option<String>,
+    pub playlist: bool,
+    pub entries: Vec<ResolvedMedia>,
+}
+
+/// Why a download failed, with the last lines of the tool's error output if there was one
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadError {
+    pub error: String,
+    pub stderr: String,
+}
+
+impl DownloadError {
+    pub fn new(error: String) -> Self {
+        DownloadError {
+            error,
+            stderr: String::new(),
+        }
+    }
+}
+
+/// What a backend reports about a file once it is done with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FinalFile {
+    pub path: String,
+    pub container: Option<String>,
+    pub duration: Option<f64>,
+}
+
+/// Where and how a resolved media is downloaded
+#[derive(Debug)]
+pub struct DownloadTarget<'a> {
+    pub directory: &'a str,
+    pub name: &'a str, // File name without extension, the cancel handle tracks the download by it
+    pub format: &'a FormatOptions,
+    pub cancel_handle: &'a CancelHandle,
+}
+
+/// Finds the media behind urls and downloads it.
+/// Backends that download nothing return the file they were given.
+pub trait MediaResolver: Send + Sync + fmt::Debug {
+    /// Lists the media behind a url, a flat resolution only needs ids, titles and urls
+    fn resolve(&self, url: &str, flat: bool) -> Result<Resolution, io::Error>;
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError>;
+}
+
+/// Resolver backends that can be picked on the command line
+pub const RESOLVER_NAMES: [&str; 5] = ["auto", "yt-dlp", "youtube-dl", "http", "local"];
+
+/// Creates the resolver with the given name, see RESOLVER_NAMES
+pub fn resolver_by_name(name: &str) -> Result<Arc<dyn MediaResolver>, io::Error> {
+    let resolver: Arc<dyn MediaResolver> = match name {
+        "auto" => Arc::new(AutoResolver::default()),
+        "yt-dlp" => Arc::new(YtDlpResolver),
+        "youtube-dl" => Arc::new(YoutubeDlResolver),
+        "http" => Arc::new(HttpResolver),
+        "local" => Arc::new(LocalFileResolver),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown resolver {}, expected one of {}",
+                    name,
+                    RESOLVER_NAMES.join(", ")
+                ),
+            ))
+        }
+    };
+    Ok(resolver)
+}
+
+/// How many lines of yt-dlp's error output are kept for a failure
+const STDERR_TAIL_LINES: usize = 20;
+
+// Makes yt-dlp print the info JSON, including the final path, after merging and moving the file
+const FINAL_INFO_PRINT: &str = "after_move:%()j";
+
+/// Parses the info JSON line printed with FINAL_INFO_PRINT, other output lines return None
+pub fn parse_final_info(line: &str) -> Option<FinalFile> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let info: serde_json::Value = serde_json::from_str(line).ok()?;
+    let path = info
+        .get("filepath")
+        .or_else(|| info.get("_filename"))
+        .and_then(|value| value.as_str())?
+        .to_string();
+    Some(FinalFile {
+        path,
+        container: info
+            .get("ext")
+            .and_then(|value| value.as_str())
+            .map(|value| value.to_string()),
+        duration: info.get("duration").and_then(|value| value.as_f64()),
+    })
+}
+
+// Last lines of yt-dlp's error output, enough to tell what went wrong
+fn stderr_tail(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+// Runs one yt-dlp download and reports its progress through on_progress
+fn run_yt_dlp(
+    url: &str,
+    target: &DownloadTarget,
+    on_progress: &dyn Fn(DownloadProgress),
+) -> Result<FinalFile, DownloadError> {
+    let output = target.directory.to_string() + "/" + target.name + ".%(ext)s";
+    let mut command = Command::new("yt-dlp");
+    // --print would make yt-dlp quiet and skip the download without the
+    // progress and no-simulate flags
+    command.args([
+        "--newline",
+        "--progress",
+        "--no-simulate",
+        "--print",
+        FINAL_INFO_PRINT,
+        "-o",
+        &output,
+        url,
+        "-i",
+    ]);
+    if let Some(selector) = target.format.selector() {
+        command.args(["-f", &selector]);
+    }
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| DownloadError::new(format!("Could not run yt-dlp: {}", error)))?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    // Read on its own thread so a chatty stderr can not block stdout
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = String::new();
+        let _ = BufReader::new(stderr).read_to_string(&mut text);
+        text
+    });
+    if !target.cancel_handle.register(target.name, child) {
+        return Err(DownloadError::new("Cancelled".to_string()));
+    }
+    let mut final_file = None;
+    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+        if let Some(update) = parse_progress_line(&line) {
+            on_progress(update);
+        } else if let Some(file) = parse_final_info(&line) {
+            final_file = Some(file);
+        }
+    }
+    let status = match target.cancel_handle.finish(target.name) {
+        Some(mut child) => child.wait(),
+        None => return Err(DownloadError::new("Cancelled".to_string())),
+    };
+    let stderr = stderr_tail(&stderr_reader.join().unwrap_or_default());
+    // The file yt-dlp reported is only trusted if it actually exists
+    let error = match final_file {
+        Some(file) if path::Path::new(&file.path).is_file() => return Ok(file),
+        Some(file) => format!("yt-dlp reported missing file {}", file.path),
+        None => match status {
+            Ok(status) => format!("yt-dlp failed with {}", status),
+            Err(error) => format!("Could not wait for yt-dlp: {}", error),
+        },
+    };
+    Err(DownloadError { error, stderr })
+}
+
+fn json_string(value: &serde_json::Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .find_map(|key| value.get(*key).and_then(|field| field.as_str()))
+        .map(|field| field.to_string())
+}
+
+fn media_from_json(value: &serde_json::Value) -> Option<ResolvedMedia> {
+    let id = json_string(value, &["id"])?;
+    let url = json_string(value, &["webpage_url", "url"])?;
+    Some(ResolvedMedia {
+        id,
+        // Flat playlist entries only name the extractor through ie_key
+        extractor: json_string(value, &["extractor_key", "ie_key", "extractor"])
+            .unwrap_or_else(|| "generic".to_string())
+            .to_lowercase(),
+        title: json_string(value, &["title"]),
+        url,
+        duration: value.get("duration").and_then(|field| field.as_f64()),
+    })
+}
+
+/// Parses the JSON yt-dlp prints with -J, for a single video or a playlist
+pub fn parse_yt_dlp_json(json: &str) -> Result<Resolution, io::Error> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let title = json_string(&value, &["title"]);
+    if let Some(entries) = value.get("entries").and_then(|field| field.as_array()) {
+        return Ok(Resolution {
+            title,
+            playlist: true,
+            entries: entries.iter().filter_map(media_from_json).collect(),
+        });
+    }
+    let media = media_from_json(&value).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "yt-dlp output has no id or url")
+    })?;
+    Ok(Resolution {
+        title,
+        playlist: false,
+        entries: vec![media],
+    })
+}
+
+/// Resolves and downloads through the yt-dlp executable
+#[derive(Debug, Clone, Copy, Default)]
+pub struct YtDlpResolver;
+
+impl MediaResolver for YtDlpResolver {
+    fn resolve(&self, url: &str, flat: bool) -> Result<Resolution, io::Error> {
+        let mut command = Command::new("yt-dlp");
+        command.args(["-J", "--socket-timeout", "15"]);
+        if flat {
+            command.arg("--flat-playlist");
+        }
+        let output = command.arg(url).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "yt-dlp failed with {}: {}",
+                    output.status,
+                    stderr_tail(&String::from_utf8_lossy(&output.stderr))
+                ),
+            ));
+        }
+        parse_yt_dlp_json(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        run_yt_dlp(&media.url, target, on_progress)
+    }
+}
+
+fn media_from_video(video: youtube_dl::SingleVideo) -> Option<ResolvedMedia> {
+    let url = video.webpage_url.clone().or_else(|| video.url.clone())?;
+    Some(ResolvedMedia {
+        extractor: video
+            .extractor_key
+            .or(video.extractor)
+            .unwrap_or_else(|| "generic".to_string())
+            .to_lowercase(),
+        title: video.title,
+        url,
+        duration: video.duration.as_ref().and_then(|value| value.as_f64()),
+        id: video.id,
+    })
+}
+
+/// Resolves through the youtube_dl crate, downloads still go through yt-dlp
+#[derive(Debug, Clone, Copy, Default)]
+pub struct YoutubeDlResolver;
+
+impl MediaResolver for YoutubeDlResolver {
+    fn resolve(&self, url: &str, flat: bool) -> Result<Resolution, io::Error> {
+        let output = YoutubeDl::new(url)
+            .socket_timeout("15")
+            .flat_playlist(flat)
+            .run()
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error.to_string()))?;
+        debug!("Output object {:?}", output);
+        Ok(match output {
+            YoutubeDlOutput::SingleVideo(value) => Resolution {
+                title: value.title.clone(),
+                playlist: false,
+                entries: media_from_video(*value).into_iter().collect(),
+            },
+            YoutubeDlOutput::Playlist(value) => Resolution {
+                title: value.title,
+                playlist: true,
+                entries: value
+                    .entries
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(media_from_video)
+                    .collect(),
+            },
+        })
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        run_yt_dlp(&media.url, target, on_progress)
+    }
+}
+
+// File extensions the http resolver takes as direct media links
+const MEDIA_EXTENSIONS: [&str; 12] = [
+    "mp4", "mkv", "webm", "mov", "avi", "m4v", "mp3", "m4a", "ogg", "opus", "flac", "wav",
+];
+
+/// Extension of the last path segment of a url, without query or fragment
+pub fn url_extension(url: &str) -> Option<String> {
+    let url = url.split(['?', '#']).next()?;
+    let segment = url.rsplit('/').next()?;
+    let (_, extension) = segment.rsplit_once('.')?;
+    Some(extension.to_lowercase())
+}
+
+/// True for http(s) urls that point straight at a media file
+pub fn is_direct_media_url(url: &str) -> bool {
+    (url.starts_with("http://") || url.starts_with("https://"))
+        && url_extension(url).map_or(false, |extension| {
+            MEDIA_EXTENSIONS.contains(&extension.as_str())
+        })
+}
+
+/// Stable id for a url, file names are derived from it so it has to survive restarts
+pub fn url_id(url: &str) -> String {
+    // 64 bit FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in url.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Plain media files over http(s), downloaded natively with resume support
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HttpResolver;
+
+impl MediaResolver for HttpResolver {
+    fn resolve(&self, url: &str, _flat: bool) -> Result<Resolution, io::Error> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a http url", url),
+            ));
+        }
+        let title = url
+            .split(['?', '#'])
+            .next()
+            .and_then(|value| value.rsplit('/').next())
+            .filter(|value| !value.is_empty())
+            .unwrap_or(url)
+            .to_string();
+        Ok(Resolution {
+            title: None,
+            playlist: false,
+            entries: vec![ResolvedMedia {
+                id: url_id(url),
+                extractor: "http".to_string(),
+                title: Some(title),
+                url: url.to_string(),
+                duration: None,
+            }],
+        })
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        download_http(&media.url, target, on_progress)
+    }
+}
+
+fn media_from_path(file: &path::Path) -> ResolvedMedia {
+    let file_path = file.to_string_lossy().to_string();
+    ResolvedMedia {
+        id: file_path.clone(),
+        extractor: "local".to_string(),
+        title: file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string()),
+        url: file_path,
+        duration: None,
+    }
+}
+
+/// Local files and directories, nothing is downloaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFileResolver;
+
+impl MediaResolver for LocalFileResolver {
+    fn resolve(&self, url: &str, _flat: bool) -> Result<Resolution, io::Error> {
+        let full_path = fs::canonicalize(url)?;
+        if !full_path.is_dir() {
+            return Ok(Resolution {
+                title: None,
+                playlist: false,
+                entries: vec![media_from_path(&full_path)],
+            });
+        }
+        // Directories list their files the way the client queues them
+        let mut files: Vec<path::PathBuf> = fs::read_dir(&full_path)?
+            .filter_map(|dir_entry| dir_entry.ok().map(|value| value.path()))
+            .filter(|file| file.is_file())
+            .filter(|file| {
+                !file
+                    .file_name()
+                    .map_or(true, |name| name.to_string_lossy().starts_with('.'))
+            })
+            .collect();
+        files.sort();
+        Ok(Resolution {
+            title: full_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            playlist: true,
+            entries: files.iter().map(|file| media_from_path(file)).collect(),
+        })
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        _target: &DownloadTarget,
+        _on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        let file = path::Path::new(&media.url);
+        if !file.is_file() {
+            return Err(DownloadError::new(format!("{} does not exist", media.url)));
+        }
+        Ok(FinalFile {
+            path: media.url.clone(),
+            container: file
+                .extension()
+                .map(|value| value.to_string_lossy().to_string()),
+            duration: media.duration,
+        })
+    }
+}
+
+/// Picks a backend per url: local paths, direct media links, and yt-dlp for the rest
+#[derive(Debug, Default)]
+pub struct AutoResolver {
+    yt_dlp: YtDlpResolver,
+    http: HttpResolver,
+    local: LocalFileResolver,
+}
+
+impl AutoResolver {
+    fn for_url(&self, url: &str) -> &dyn MediaResolver {
+        if path::Path::new(url).exists() {
+            &self.local
+        } else if is_direct_media_url(url) {
+            &self.http
+        } else {
+            &self.yt_dlp
+        }
+    }
+}
+
+impl MediaResolver for AutoResolver {
+    fn resolve(&self, url: &str, flat: bool) -> Result<Resolution, io::Error> {
+        let resolver = self.for_url(url);
+        debug!("Resolving {} with {:?}", url, resolver);
+        resolver.resolve(url, flat)
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        let resolver: &dyn MediaResolver = match media.extractor.as_str() {
+            "local" => &self.local,
+            "http" => &self.http,
+            _ => &self.yt_dlp,
+        };
+        resolver.download(media, target, on_progress)
+    }
+}
+
+// Outcomes of the next downloads by media id, file contents or an error
+#[cfg(test)]
+type ScriptedDownloads =
+    std::collections::HashMap<String, std::collections::VecDeque<Result<Vec<u8>, String>>>;
+
+/// Resolver with canned answers, so downloads can be tested without network
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ScriptedResolver {
+    resolutions: std::collections::HashMap<String, Resolution>,
+    downloads: std::sync::Mutex<ScriptedDownloads>,
+}
+
+#[cfg(test)]
+impl ScriptedResolver {
+    pub fn video(id: &str, title: &str) -> ResolvedMedia {
+        ResolvedMedia {
+            id: id.to_string(),
+            extractor: "youtube".to_string(),
+            title: Some(title.to_string()),
+            url: "https://www.youtube.com/watch?v=".to_string() + id,
+            duration: Some(60.0),
+        }
+    }
+
+    pub fn resolves(mut self, url: &str, resolution: Resolution) -> Self {
+        self.resolutions.insert(url.to_string(), resolution);
+        self
+    }
+
+    pub fn downloads(self, id: &str, outcomes: Vec<Result<&[u8], &str>>) -> Self {
+        self.downloads.lock().unwrap().insert(
+            id.to_string(),
+            outcomes
+                .into_iter()
+                .map(|outcome| {
+                    outcome
+                        .map(|contents| contents.to_vec())
+                        .map_err(|error| error.to_string())
+                })
+                .collect(),
+        );
+        self
+    }
+
+    /// Scripted downloads that have not happened yet
+    pub fn pending_downloads(&self) -> usize {
+        self.downloads
+            .lock()
+            .unwrap()
+            .values()
+            .map(|outcomes| outcomes.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+impl MediaResolver for ScriptedResolver {
+    fn resolve(&self, url: &str, _flat: bool) -> Result<Resolution, io::Error> {
+        self.resolutions.get(url).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Nothing scripted for {}", url),
+            )
+        })
+    }
+
+    fn download(
+        &self,
+        media: &ResolvedMedia,
+        target: &DownloadTarget,
+        on_progress: &dyn Fn(DownloadProgress),
+    ) -> Result<FinalFile, DownloadError> {
+        let outcome = self
+            .downloads
+            .lock()
+            .unwrap()
+            .get_mut(&media.id)
+            .and_then(|outcomes| outcomes.pop_front());
+        let contents = match outcome {
+            Some(Ok(contents)) => contents,
+            Some(Err(error)) => {
+                return Err(DownloadError {
+                    error,
+                    stderr: "ERROR: scripted failure".to_string(),
+                })
+            }
+            None => {
+                return Err(DownloadError::new(format!(
+                    "Nothing scripted for {}",
+                    media.id
+                )));
+            }
+        };
+        let file_path = format!("{}/{}.mp4", target.directory, target.name);
+        fs::write(&file_path, &contents).map_err(|error| DownloadError::new(error.to_string()))?;
+        on_progress(DownloadProgress {
+            percent: 100.0,
+            total_bytes: Some(contents.len() as u64),
+            downloaded_bytes: Some(contents.len() as u64),
+            ..Default::default()
+        });
+        Ok(FinalFile {
+            path: file_path,
+            container: Some("mp4".to_string()),
+            duration: media.duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_final_info() {
+        let line = r#"{"id": "138ajKRMzIY", "ext": "mkv", "duration": 212.0, "filepath": "/tmp/youtube-138ajKRMzIY.mkv", "requested_downloads": [{"filepath": "/tmp/youtube-138ajKRMzIY.f251.webm"}]}"#;
+        assert_eq!(
+            parse_final_info(line),
+            Some(FinalFile {
+                path: "/tmp/youtube-138ajKRMzIY.mkv".to_string(),
+                container: Some("mkv".to_string()),
+                duration: Some(212.0),
+            })
+        );
+        assert!(parse_final_info("[Merger] Merging formats into \"/tmp/a.mkv\"").is_none());
+        assert!(parse_final_info(r#"{"id": "no path"}"#).is_none());
+
+        let mut stderr = String::new();
+        for line in 1..=30 {
+            stderr += &format!("line {}\n", line);
+        }
+        assert!(stderr_tail(&stderr).starts_with("line 11\n"));
+        assert!(stderr_tail(&stderr).ends_with("line 30"));
+    }
+
+    #[test]
+    fn test_parse_yt_dlp_json() {
+        let playlist = r#"{"_type": "playlist", "title": "Demo", "entries": [
+            {"id": "138ajKRMzIY", "ie_key": "Youtube", "title": "First", "url": "https://www.youtube.com/watch?v=138ajKRMzIY", "duration": 212.0},
+            {"id": "1", "ie_key": "Vimeo", "title": "Second", "url": "https://vimeo.com/1"},
+            {"title": "No id"}
+        ]}"#;
+        let resolution = parse_yt_dlp_json(playlist).unwrap();
+        assert!(resolution.playlist);
+        assert_eq!(resolution.title, Some("Demo".to_string()));
+        assert_eq!(resolution.entries.len(), 2);
+        assert_eq!(
+            resolution.entries[0].entry_string(),
+            Some("138ajKRMzIY - First".to_string())
+        );
+        assert_eq!(resolution.entries[0].cache_key(), "youtube-138ajKRMzIY");
+        assert_eq!(
+            resolution.entries[1].entry_string(),
+            Some("https://vimeo.com/1 - Second".to_string())
+        );
+
+        let video = r#"{"id": "138ajKRMzIY", "extractor_key": "Youtube", "title": "Only", "webpage_url": "https://www.youtube.com/watch?v=138ajKRMzIY"}"#;
+        let resolution = parse_yt_dlp_json(video).unwrap();
+        assert!(!resolution.playlist);
+        assert_eq!(resolution.entries[0].extractor, "youtube");
+        assert!(parse_yt_dlp_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_auto_resolver_picks_backend() {
+        let auto = AutoResolver::default();
+        assert!(is_direct_media_url(
+            "https://example.com/files/clip.MP4?token=1"
+        ));
+        assert!(!is_direct_media_url(
+            "https://www.youtube.com/watch?v=138ajKRMzIY"
+        ));
+        assert!(!is_direct_media_url("/media/clip.mp4"));
+
+        let resolution = auto
+            .resolve("https://example.com/files/clip.mp4", false)
+            .unwrap();
+        let media = &resolution.entries[0];
+        assert_eq!(media.extractor, "http");
+        assert_eq!(media.title, Some("clip.mp4".to_string()));
+        assert_eq!(media.id, url_id("https://example.com/files/clip.mp4"));
+
+        let file = std::env::temp_dir().join(format!("smqueue-local-{}.mkv", uuid::Uuid::new_v4()));
+        fs::write(&file, b"data").unwrap();
+        let resolution = auto.resolve(file.to_str().unwrap(), false).unwrap();
+        assert_eq!(resolution.entries[0].extractor, "local");
+        let cancel_handle = CancelHandle::new("/tmp".to_string());
+        let target = DownloadTarget {
+            directory: "/tmp",
+            name: "unused",
+            format: &FormatOptions::default(),
+            cancel_handle: &cancel_handle,
+        };
+        // Local files are played where they are
+        let final_file = auto
+            .download(&resolution.entries[0], &target, &|_| {})
+            .unwrap();
+        assert_eq!(final_file.path, resolution.entries[0].url);
+        assert_eq!(final_file.container, Some("mkv".to_string()));
+        fs::remove_file(&file).unwrap();
+
+        assert!(resolver_by_name("youtube-dl").is_ok());
+        assert!(resolver_by_name("vlc").is_err());
+    }
+}
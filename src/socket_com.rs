@@ -4,20 +4,28 @@ use std::io;
 use std::io::prelude::*;
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use youtube_dl::{YoutubeDl, YoutubeDlOutput};
+use chrono::{Local, NaiveDateTime, NaiveTime, TimeZone};
+use rand::seq::SliceRandom;
+
+use crate::media_resolver::{is_direct_media_url, AutoResolver, MediaResolver, Resolution};
+use crate::status_watcher::RepeatMode;
 
 // Logging
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
 #[derive(Debug, Clone)]
 enum MessageType {
     QueueEntryRequest = 0,
     QueueControlRequest,
+    Scheduled,
+    Grouped,   // Wraps an entry request with the group it belongs to
+    Formatted, // Wraps an entry request with its format options
 }
 
 #[derive(Debug, Clone)]
-enum ControlCommand {
+pub enum ControlCommand {
     ClearQueue = 0,
     StopPlayback,
     PausePlayback,
@@ -25,15 +33,336 @@ enum ControlCommand {
     SkipAndPlay,
     RemoveFromQueue,
     PromoteEntry,
+    SetRepeatMode,
+    SetShuffle,
+    Undo,
+    RemoveGroup,
+    PromoteGroup,
+    SetGroupPriority, // Uses the message priority as the new priority
+    SetMute,          // Mutes mpv's output, 1 mutes and 0 unmutes
+    SetVolume,        // Sets mpv's volume in percent
 }
 
 #[derive(Debug, Clone)]
-enum EntryType {
+pub enum EntryType {
     YoutubeMedia = 0,
     FileStream,
     LocalMedia,
     Command,
     Unknown,
+    YoutubePlaylist, // Expanded by the daemon once it reaches the front of the queue
+}
+
+/// Controls which playlist entries are queued and in what order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaylistOptions {
+    /// 1-based inclusive range of playlist items, like yt-dlp's --playlist-items
+    pub items: Option<(u32, u32)>,
+    pub max_count: Option<u32>,
+    pub reverse: bool,
+    pub shuffle: bool,
+    /// Queue the playlist as one entry and expand it when it is about to play
+    pub lazy: bool,
+}
+
+const PLAYLIST_OPTIONS_SIZE: usize = 13;
+
+impl PlaylistOptions {
+    /// Applies range, reverse, max count and shuffle in that order
+    pub fn select<T>(&self, mut entries: Vec<T>) -> Vec<T> {
+        if let Some((start, end)) = self.items {
+            let start = (start.saturating_sub(1) as usize).min(entries.len());
+            let end = (end as usize).min(entries.len()).max(start);
+            entries = entries.drain(start..end).collect();
+        }
+        if self.reverse {
+            entries.reverse();
+        }
+        if let Some(max_count) = self.max_count {
+            entries.truncate(max_count as usize);
+        }
+        if self.shuffle {
+            entries.shuffle(&mut rand::thread_rng());
+        }
+        entries
+    }
+
+    // Wire format: items start u32, items end u32, max count u32 (0 means unset), flags u8
+    fn to_bytes(&self) -> Vec<u8> {
+        let (start, end) = self.items.unwrap_or((0, 0));
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&start.to_le_bytes());
+        bytes.extend_from_slice(&end.to_le_bytes());
+        bytes.extend_from_slice(&self.max_count.unwrap_or(0).to_le_bytes());
+        bytes.push(self.reverse as u8 | (self.shuffle as u8) << 1);
+        bytes
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < PLAYLIST_OPTIONS_SIZE {
+            return None;
+        }
+        let read_u32 = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let (start, end, max_count) = (read_u32(0), read_u32(4), read_u32(8));
+        Some(PlaylistOptions {
+            items: if start == 0 { None } else { Some((start, end)) },
+            max_count: if max_count == 0 {
+                None
+            } else {
+                Some(max_count)
+            },
+            reverse: data[12] & 1 != 0,
+            shuffle: data[12] & 2 != 0,
+            lazy: true,
+        })
+    }
+}
+
+/// Which formats yt-dlp and mpv pick for an entry, unset fields fall back to the daemon defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormatOptions {
+    pub max_height: Option<u32>,  // e.g. 720 for at most 720p
+    pub codec: Option<String>,    // Prefix of the video codec, or audio codec when audio only
+    pub audio_only: Option<bool>, // Some(false) fetches the video even if audio only is the default
+}
+
+impl FormatOptions {
+    /// Fills unset fields from the defaults
+    pub fn or(&self, defaults: &FormatOptions) -> FormatOptions {
+        FormatOptions {
+            max_height: self.max_height.or(defaults.max_height),
+            codec: self.codec.clone().or_else(|| defaults.codec.clone()),
+            audio_only: self.audio_only.or(defaults.audio_only),
+        }
+    }
+
+    /// yt-dlp format selector, also used as mpv's ytdl-format. None keeps the default.
+    pub fn selector(&self) -> Option<String> {
+        let audio_only = self.audio_only == Some(true);
+        if self.max_height.is_none() && self.codec.is_none() && !audio_only {
+            return None;
+        }
+        let codec = |field: &str| match &self.codec {
+            Some(codec) => format!("[{}^={}]", field, codec),
+            None => "".to_string(),
+        };
+        if audio_only {
+            let preferred = format!("bestaudio{}", codec("acodec"));
+            return Some(match self.codec {
+                Some(_) => preferred + "/bestaudio/best",
+                None => preferred + "/best",
+            });
+        }
+        let height = match self.max_height {
+            Some(value) => format!("[height<={}]", value),
+            None => "".to_string(),
+        };
+        let mut selector = format!("bestvideo{}{}+bestaudio/", height, codec("vcodec"));
+        if self.codec.is_some() {
+            selector += &format!("bestvideo{}+bestaudio/", height);
+        }
+        selector += &format!("best{}/best", height);
+        Some(selector)
+    }
+
+    /// Added to cache keys so different formats of one video are cached separately
+    pub fn cache_suffix(&self) -> String {
+        let mut suffix = String::new();
+        if let Some(height) = self.max_height {
+            suffix += &format!("-h{}", height);
+        }
+        if let Some(codec) = &self.codec {
+            let codec: String = codec
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .collect();
+            suffix += &format!("-{}", codec);
+        }
+        if self.audio_only == Some(true) {
+            suffix += "-audio";
+        }
+        suffix
+    }
+
+    // Wire format: max height u32 (0 means unset), flags u8, codec length u8, codec.
+    // Flag 1 is audio only, flag 2 marks it as set.
+    fn to_bytes(&self) -> Vec<u8> {
+        let codec = self.codec.clone().unwrap_or_default();
+        let codec = &codec.as_bytes()[..codec.len().min(u8::MAX as usize)];
+        let mut bytes = self.max_height.unwrap_or(0).to_le_bytes().to_vec();
+        bytes.push(match self.audio_only {
+            Some(value) => 2 | value as u8,
+            None => 0,
+        });
+        bytes.push(codec.len() as u8);
+        bytes.extend_from_slice(codec);
+        bytes
+    }
+
+    // Returns the options and the number of bytes they took
+    fn from_bytes(data: &[u8]) -> Option<(Self, usize)> {
+        if data.len() < 6 {
+            return None;
+        }
+        let mut height = [0u8; 4];
+        height.copy_from_slice(&data[..4]);
+        let height = u32::from_le_bytes(height);
+        let codec_length = data[5] as usize;
+        let codec = data.get(6..6 + codec_length)?;
+        let options = FormatOptions {
+            max_height: if height == 0 { None } else { Some(height) },
+            codec: if codec.is_empty() {
+                None
+            } else {
+                Some(String::from_utf8_lossy(codec).to_string())
+            },
+            audio_only: match data[4] & 3 {
+                0 => None,
+                flags => Some(flags & 1 != 0),
+            },
+        };
+        Some((options, 6 + codec_length))
+    }
+}
+
+// Wraps an entry message with its format options unless they are all defaults
+fn with_format(message: Message, format: &FormatOptions) -> Message {
+    if *format == FormatOptions::default() {
+        return message;
+    }
+    let mut data = format.to_bytes();
+    data.push(message.Type as u8);
+    data.extend(message.Data);
+    Message {
+        Type: MessageType::Formatted,
+        Priority: message.Priority,
+        Data: data,
+    }
+}
+
+/// Parses "START-END", "START-" or "N" into a 1-based inclusive range
+pub fn parse_playlist_items(input: &str) -> Result<(u32, u32), io::Error> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Expected playlist items as START-END, START- or N",
+        )
+    };
+    let (start, end) = match input.split_once('-') {
+        Some((start, "")) => (start.parse::<u32>().map_err(|_| invalid())?, u32::MAX),
+        Some((start, end)) => (
+            start.parse::<u32>().map_err(|_| invalid())?,
+            end.parse::<u32>().map_err(|_| invalid())?,
+        ),
+        None => {
+            let item = input.parse::<u32>().map_err(|_| invalid())?;
+            (item, item)
+        }
+    };
+    if start == 0 || end < start {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+// Entries queued together only get a group when there is more than one of them
+fn new_group_id(entry_count: usize) -> Option<u64> {
+    if entry_count > 1 {
+        Some(rand::random::<u64>())
+    } else {
+        None
+    }
+}
+
+/// When a scheduled entry should be released into the queue.
+/// Timestamps are unix seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// Queued as normal once the timestamp has passed
+    NotBefore(i64),
+    /// Preempts whatever is playing at the timestamp
+    StartAt(i64),
+}
+
+impl Schedule {
+    pub fn timestamp(&self) -> i64 {
+        match self {
+            Schedule::NotBefore(value) => *value,
+            Schedule::StartAt(value) => *value,
+        }
+    }
+    /// Formats the schedule the way it is shown in the queue, e.g. "at 16:00"
+    pub fn describe(&self) -> String {
+        // Timestamps come from clients and the queue file, ones without a local time are shown raw
+        let time_str = match Local.timestamp_opt(self.timestamp(), 0).earliest() {
+            Some(time) if time.date_naive() == Local::now().date_naive() => {
+                time.format("%H:%M").to_string()
+            }
+            Some(time) => time.format("%H:%M %d-%b").to_string(),
+            None => self.timestamp().to_string(),
+        };
+        match self {
+            Schedule::NotBefore(_) => "after ".to_string() + &time_str,
+            Schedule::StartAt(_) => "at ".to_string() + &time_str,
+        }
+    }
+}
+
+/// Parses "HH:MM" (next occurrence) or "YYYY-MM-DD HH:MM" into a unix timestamp
+pub fn parse_schedule_time(input: &str) -> Result<i64, io::Error> {
+    let now = Local::now();
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M") {
+        return match Local.from_local_datetime(&date_time).earliest() {
+            Some(value) => Ok(value.timestamp()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Time does not exist in local timezone",
+            )),
+        };
+    }
+    let time = match NaiveTime::parse_from_str(input, "%H:%M") {
+        Ok(value) => value,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected time as HH:MM or YYYY-MM-DD HH:MM",
+            ))
+        }
+    };
+    let mut date_time = now.date_naive().and_time(time);
+    if date_time <= now.naive_local() {
+        date_time += chrono::Duration::days(1);
+    }
+    match Local.from_local_datetime(&date_time).earliest() {
+        Some(value) => Ok(value.timestamp()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Time does not exist in local timezone",
+        )),
+    }
+}
+
+/// A message as seen by the daemon after decoding
+#[derive(Debug)]
+pub enum Request {
+    QueueEntry {
+        priority: u64,
+        entry_type: EntryType,
+        location: String,
+        schedule: Option<Schedule>,
+        playlist: Option<PlaylistOptions>,
+        group: Option<u64>,
+        format: Option<FormatOptions>,
+    },
+    Control {
+        command: ControlCommand,
+        argument: Option<u64>, // Queue id, group id or the value to set
+        priority: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -44,17 +373,204 @@ struct Message {
 }
 
 #[derive(Debug)]
-pub struct SocketCom {}
+pub struct SocketCom {
+    resolver: Arc<dyn MediaResolver>, // Tells media urls apart from other input
+}
 pub const DEFAULT_PRIORITY: u64 = 50;
+pub const SOCKET_PATH: &str = "/tmp/media_queue.sock";
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MessageType::QueueEntryRequest),
+            1 => Some(MessageType::QueueControlRequest),
+            2 => Some(MessageType::Scheduled),
+            3 => Some(MessageType::Grouped),
+            4 => Some(MessageType::Formatted),
+            _ => None,
+        }
+    }
+}
+
+impl EntryType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(EntryType::YoutubeMedia),
+            1 => Some(EntryType::FileStream),
+            2 => Some(EntryType::LocalMedia),
+            3 => Some(EntryType::Command),
+            4 => Some(EntryType::Unknown),
+            5 => Some(EntryType::YoutubePlaylist),
+            _ => None,
+        }
+    }
+}
+
+impl ControlCommand {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ControlCommand::ClearQueue),
+            1 => Some(ControlCommand::StopPlayback),
+            2 => Some(ControlCommand::PausePlayback),
+            3 => Some(ControlCommand::StartPlayback),
+            4 => Some(ControlCommand::SkipAndPlay),
+            5 => Some(ControlCommand::RemoveFromQueue),
+            6 => Some(ControlCommand::PromoteEntry),
+            7 => Some(ControlCommand::SetRepeatMode),
+            8 => Some(ControlCommand::SetShuffle),
+            9 => Some(ControlCommand::Undo),
+            10 => Some(ControlCommand::RemoveGroup),
+            11 => Some(ControlCommand::PromoteGroup),
+            12 => Some(ControlCommand::SetGroupPriority),
+            13 => Some(ControlCommand::SetMute),
+            14 => Some(ControlCommand::SetVolume),
+            _ => None,
+        }
+    }
+}
+
+fn read_u64(data: &[u8]) -> Option<u64> {
+    if data.len() < 8 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn invalid_message(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Decodes a raw unix socket message into a daemon request
+pub fn decode_message(raw: &[u8]) -> Result<Request, io::Error> {
+    let priority = read_u64(raw).ok_or_else(|| invalid_message("Message too short"))?;
+    let message_type = raw
+        .get(8)
+        .and_then(|value| MessageType::from_u8(*value))
+        .ok_or_else(|| invalid_message("Unknown message type"))?;
+    let data = &raw[9..];
+    trace!("Decoding message type {:?} data {:?}", message_type, data);
+    match message_type {
+        MessageType::QueueEntryRequest | MessageType::Scheduled => {
+            let mut data = data;
+            let mut schedule = None;
+            if let MessageType::Scheduled = message_type {
+                let kind = *data
+                    .first()
+                    .ok_or_else(|| invalid_message("Missing schedule"))?;
+                let timestamp =
+                    read_u64(&data[1..]).ok_or_else(|| invalid_message("Missing schedule"))? as i64;
+                schedule = match kind {
+                    0 => Some(Schedule::NotBefore(timestamp)),
+                    1 => Some(Schedule::StartAt(timestamp)),
+                    _ => return Err(invalid_message("Unknown schedule kind")),
+                };
+                data = &data[9..];
+            }
+            let entry_type = data
+                .first()
+                .and_then(|value| EntryType::from_u8(*value))
+                .ok_or_else(|| invalid_message("Unknown entry type"))?;
+            let mut data = &data[1..];
+            let mut playlist = None;
+            if let EntryType::YoutubePlaylist = entry_type {
+                playlist = Some(
+                    PlaylistOptions::from_bytes(data)
+                        .ok_or_else(|| invalid_message("Missing playlist options"))?,
+                );
+                data = &data[PLAYLIST_OPTIONS_SIZE..];
+            }
+            Ok(Request::QueueEntry {
+                priority,
+                entry_type,
+                location: String::from_utf8_lossy(data).to_string(),
+                schedule,
+                playlist,
+                group: None,
+                format: None,
+            })
+        }
+        MessageType::QueueControlRequest => {
+            let command = data
+                .first()
+                .and_then(|value| ControlCommand::from_u8(*value))
+                .ok_or_else(|| invalid_message("Unknown control command"))?;
+            Ok(Request::Control {
+                command,
+                argument: read_u64(&data[1..]),
+                priority,
+            })
+        }
+        MessageType::Grouped => {
+            let group = read_u64(data).ok_or_else(|| invalid_message("Missing group"))?;
+            // The wrapped request is decoded as if it was sent on its own
+            let mut inner: Vec<u8> = priority.to_le_bytes().to_vec();
+            inner.extend_from_slice(&data[8..]);
+            match decode_message(&inner)? {
+                Request::QueueEntry {
+                    priority,
+                    entry_type,
+                    location,
+                    schedule,
+                    playlist,
+                    group: None,
+                    format,
+                } => Ok(Request::QueueEntry {
+                    priority,
+                    entry_type,
+                    location,
+                    schedule,
+                    playlist,
+                    group: Some(group),
+                    format,
+                }),
+                _ => Err(invalid_message("Group must wrap a single entry request")),
+            }
+        }
+        MessageType::Formatted => {
+            let (options, length) =
+                FormatOptions::from_bytes(data).ok_or_else(|| invalid_message("Missing format"))?;
+            let mut inner: Vec<u8> = priority.to_le_bytes().to_vec();
+            inner.extend_from_slice(&data[length..]);
+            match decode_message(&inner)? {
+                Request::QueueEntry {
+                    priority,
+                    entry_type,
+                    location,
+                    schedule,
+                    playlist,
+                    group,
+                    format: None,
+                } => Ok(Request::QueueEntry {
+                    priority,
+                    entry_type,
+                    location,
+                    schedule,
+                    playlist,
+                    group,
+                    format: Some(options),
+                }),
+                _ => Err(invalid_message("Format must wrap a single entry request")),
+            }
+        }
+    }
+}
 
 impl SocketCom {
     pub fn new() -> Result<Self, io::Error> {
-        let com = SocketCom {};
+        let com = SocketCom {
+            resolver: Arc::new(AutoResolver::default()),
+        };
         Ok(com)
     }
 
+    pub fn set_resolver(&mut self, resolver: Arc<dyn MediaResolver>) {
+        self.resolver = resolver;
+    }
+
     fn send_message(&mut self, msg: Message) -> Result<(), io::Error> {
-        let mut stream = UnixStream::connect("/tmp/media_queue.sock")?;
+        let mut stream = UnixStream::connect(SOCKET_PATH)?;
         let mut tbs_msg: Vec<u8> = vec![];
         debug!("Sending unix socket message {:?}", msg);
         for byte in &msg.Priority.to_le_bytes() {
@@ -75,6 +591,47 @@ impl SocketCom {
 
         Ok(())
     }
+    fn entry_message(
+        &self,
+        entry_type: EntryType,
+        location: &str,
+        priority: u64,
+        schedule: Option<Schedule>,
+        playlist: Option<&PlaylistOptions>,
+        group: Option<u64>,
+    ) -> Message {
+        let mut tbs_data: Vec<u8> = vec![];
+        let mut message_type = MessageType::QueueEntryRequest;
+        if let Some(schedule) = schedule {
+            message_type = MessageType::Scheduled;
+            match schedule {
+                Schedule::NotBefore(_) => tbs_data.push(0),
+                Schedule::StartAt(_) => tbs_data.push(1),
+            }
+            for byte in &schedule.timestamp().to_le_bytes() {
+                tbs_data.push(*byte);
+            }
+        }
+        tbs_data.push(entry_type as u8);
+        if let Some(options) = playlist {
+            tbs_data.extend_from_slice(&options.to_bytes());
+        }
+        for byte in location.as_bytes() {
+            tbs_data.push(*byte);
+        }
+        if let Some(group) = group {
+            let mut grouped_data = group.to_le_bytes().to_vec();
+            grouped_data.push(message_type as u8);
+            grouped_data.append(&mut tbs_data);
+            tbs_data = grouped_data;
+            message_type = MessageType::Grouped;
+        }
+        Message {
+            Type: message_type,
+            Priority: priority,
+            Data: tbs_data,
+        }
+    }
     // Should not be used
     pub fn stop_playback(&mut self) -> Result<(), io::Error> {
         info!("Stopping playback");
@@ -126,6 +683,16 @@ impl SocketCom {
         self.send_message(tbs_message)?;
         Ok(())
     }
+    pub fn undo(&mut self) -> Result<(), io::Error> {
+        info!("Undo last queue change");
+        let tbs_message = Message {
+            Type: MessageType::QueueControlRequest,
+            Priority: DEFAULT_PRIORITY,
+            Data: vec![ControlCommand::Undo as u8],
+        };
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
     pub fn delete_entry(&mut self, queue_id: u64) -> Result<(), io::Error> {
         info!("Deleting entry id {}", queue_id);
         let mut tbs_data: Vec<u8> = vec![];
@@ -160,17 +727,72 @@ impl SocketCom {
         self.send_message(tbs_message)?;
         Ok(())
     }
+    fn control_message(&self, command: ControlCommand, argument: u64) -> Message {
+        let mut tbs_data: Vec<u8> = vec![command as u8];
+        tbs_data.extend_from_slice(&argument.to_le_bytes());
+        Message {
+            Type: MessageType::QueueControlRequest,
+            Priority: DEFAULT_PRIORITY,
+            Data: tbs_data,
+        }
+    }
+    pub fn remove_group(&mut self, group: u64) -> Result<(), io::Error> {
+        info!("Removing group {}", group);
+        let tbs_message = self.control_message(ControlCommand::RemoveGroup, group);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn promote_group(&mut self, group: u64) -> Result<(), io::Error> {
+        info!("Promoting group {}", group);
+        let tbs_message = self.control_message(ControlCommand::PromoteGroup, group);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn set_group_priority(&mut self, group: u64, priority: u64) -> Result<(), io::Error> {
+        info!("Setting priority of group {} to {}", group, priority);
+        let mut tbs_message = self.control_message(ControlCommand::SetGroupPriority, group);
+        tbs_message.Priority = priority;
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn set_repeat_mode(&mut self, mode: RepeatMode) -> Result<(), io::Error> {
+        info!("Setting repeat mode {}", mode);
+        let tbs_message = self.control_message(ControlCommand::SetRepeatMode, mode as u64);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn set_shuffle(&mut self, shuffle: bool) -> Result<(), io::Error> {
+        info!("Setting shuffle {}", shuffle);
+        let tbs_message = self.control_message(ControlCommand::SetShuffle, shuffle as u64);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn set_mute(&mut self, muted: bool) -> Result<(), io::Error> {
+        info!("Setting mute {}", muted);
+        let tbs_message = self.control_message(ControlCommand::SetMute, muted as u64);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
+    pub fn set_volume(&mut self, volume: u64) -> Result<(), io::Error> {
+        info!("Setting volume {}", volume);
+        let tbs_message = self.control_message(ControlCommand::SetVolume, volume);
+        self.send_message(tbs_message)?;
+        Ok(())
+    }
     pub fn add_entry(
         &mut self,
         entry: String,
         priority: u64,
         raw: bool,
+        schedule: Option<Schedule>,
+        playlist: &PlaylistOptions,
+        format: &FormatOptions,
     ) -> Result<String, io::Error> {
         // Should do parsing to identify if it is a youtube video or not
         let entry_clone = entry.clone();
         let md = metadata(&entry_clone);
         let mut entry_type = EntryType::Unknown;
-        let mut youtube_obj: Option<youtube_dl::YoutubeDlOutput> = None;
+        let mut resolution: Option<Resolution> = None;
         if md.is_ok() {
             let meta_data = md.unwrap();
             if meta_data.is_file() || meta_data.is_dir() {
@@ -178,99 +800,140 @@ impl SocketCom {
             } else {
                 debug!("This is not a path or direcotry {:?}", entry_clone);
             }
+        } else if is_direct_media_url(&entry_clone) {
+            // Played as a stream, the daemon downloads it natively when prefetching
+            entry_type = EntryType::FileStream;
         } else {
-            let is_youtube = YoutubeDl::new(&entry_clone).socket_timeout("5").run();
-            if is_youtube.is_ok() {
-                entry_type = EntryType::YoutubeMedia;
-                youtube_obj = Some(is_youtube.unwrap());
-            } else {
-                println!("{:?}", is_youtube);
-                debug!("Not recognized input file/url");
+            // A flat listing is enough to tell a lazily queued playlist apart
+            match self.resolver.resolve(&entry_clone, playlist.lazy) {
+                Ok(value) => {
+                    entry_type = EntryType::YoutubeMedia;
+                    resolution = Some(value);
+                }
+                Err(error) => {
+                    println!("{}", error);
+                    debug!("Not recognized input file/url");
+                }
             }
         }
         if raw {
             // Forces filestream type
             entry_type = EntryType::FileStream;
         }
-        info!("Adding entry {:?} {}", entry_type, entry);
+        info!("Adding entry {:?} {} {:?}", entry_type, entry, schedule);
+        let scheduled_suffix = match schedule {
+            Some(value) => " (".to_string() + &value.describe() + ")",
+            None => "".to_string(),
+        };
         match entry_type {
             EntryType::LocalMedia => {
-                let mut tbs_data: Vec<u8> = vec![];
-                tbs_data.push(EntryType::LocalMedia as u8);
                 let fullpath = fs::canonicalize(PathBuf::from(&entry_clone))?;
-                let fullpath_string = fullpath.into_os_string().into_string().unwrap();
-                for byte in fullpath_string.as_bytes() {
-                    tbs_data.push(*byte);
+                let fullpath_string = fullpath.clone().into_os_string().into_string().unwrap();
+                if !fullpath.is_dir() {
+                    let tbs_message = self.entry_message(
+                        EntryType::LocalMedia,
+                        &fullpath_string,
+                        priority,
+                        schedule,
+                        None,
+                        None,
+                    );
+                    self.send_message(with_format(tbs_message, format))?;
+                    return Ok("Added ".to_string() + &fullpath_string + &scheduled_suffix);
                 }
-                let tbs_message = Message {
-                    Type: MessageType::QueueEntryRequest,
-                    Priority: priority,
-                    Data: tbs_data,
-                };
-                self.send_message(tbs_message)?;
-                Ok("Added ".to_string() + &fullpath_string)
+                // Directories are queued file by file as one group
+                let mut files: Vec<PathBuf> = fs::read_dir(&fullpath)?
+                    .filter_map(|dir_entry| dir_entry.ok().map(|value| value.path()))
+                    .filter(|path| path.is_file())
+                    .filter(|path| {
+                        !path
+                            .file_name()
+                            .map_or(true, |name| name.to_string_lossy().starts_with('.'))
+                    })
+                    .collect();
+                files.sort();
+                let group = new_group_id(files.len());
+                for file in &files {
+                    let tbs_message = self.entry_message(
+                        EntryType::LocalMedia,
+                        &file.to_string_lossy(),
+                        priority,
+                        schedule,
+                        None,
+                        group,
+                    );
+                    self.send_message(with_format(tbs_message, format))?;
+                }
+                Ok(format!(
+                    "Added {} files from {}{}",
+                    files.len(),
+                    fullpath_string,
+                    scheduled_suffix
+                ))
             }
             EntryType::YoutubeMedia => {
-                let video_object = youtube_obj.unwrap();
-                let mut video_array: Vec<youtube_dl::SingleVideo> = vec![];
-                match video_object {
-                    YoutubeDlOutput::SingleVideo(value) => {
-                        let video = *value;
-                        video_array.push(video);
-                        debug!("Youtube singel video object");
-                    }
-                    YoutubeDlOutput::Playlist(value) => {
-                        let playlist = *value;
-                        for video in playlist.entries.unwrap() {
-                            video_array.push(video);
-                        }
-                        debug!("Youtube playlist object");
-                    }
+                let resolution = resolution.unwrap();
+                if resolution.playlist && playlist.lazy {
+                    let tbs_message = self.entry_message(
+                        EntryType::YoutubePlaylist,
+                        &entry,
+                        priority,
+                        schedule,
+                        Some(playlist),
+                        None,
+                    );
+                    self.send_message(with_format(tbs_message, format))?;
+                    return Ok("Added playlist ".to_string()
+                        + &resolution.title.unwrap_or(entry)
+                        + ", expanded when it is reached"
+                        + &scheduled_suffix);
                 }
+                let video_array = if resolution.playlist {
+                    debug!("Youtube playlist object");
+                    playlist.select(resolution.entries)
+                } else {
+                    debug!("Youtube singel video object");
+                    resolution.entries
+                };
+                let group = new_group_id(video_array.len());
                 let mut feedback_message: String = "".to_string();
                 for video in video_array {
-                    let mut tbs_data: Vec<u8> = vec![];
-                    tbs_data.push(EntryType::YoutubeMedia as u8);
-                    let tbs_id_string;
-                    if video.extractor == Some("youtube".to_string()) {
-                        tbs_id_string = (*video.id).to_string()
-                            + " - "
-                            + &(*video.title.clone().expect("Could not extract title"));
-                    } else {
-                        tbs_id_string = (*video.webpage_url.unwrap()).to_string()
-                            + " - "
-                            + &(*video.title.clone().expect("Could not extract title"));
-                    }
+                    let tbs_id_string = match video.entry_string() {
+                        Some(value) => value,
+                        None => {
+                            warn!("Skipping video without title or url {:?}", video.id);
+                            continue;
+                        }
+                    };
                     debug!("Youtube video add {}", &tbs_id_string);
                     feedback_message = feedback_message
                         + "Added Youtube video "
                         + &(*video.title.clone().expect("Could not extract title"))
+                        + &scheduled_suffix
                         + "\n";
-                    for byte in tbs_id_string.as_bytes() {
-                        tbs_data.push(*byte);
-                    }
-                    let tbs_message = Message {
-                        Type: MessageType::QueueEntryRequest,
-                        Priority: priority,
-                        Data: tbs_data,
-                    };
-                    self.send_message(tbs_message)?;
+                    let tbs_message = self.entry_message(
+                        EntryType::YoutubeMedia,
+                        &tbs_id_string,
+                        priority,
+                        schedule,
+                        None,
+                        group,
+                    );
+                    self.send_message(with_format(tbs_message, format))?;
                 }
                 Ok(feedback_message)
             }
             EntryType::FileStream => {
-                let mut tbs_data: Vec<u8> = vec![];
-                tbs_data.push(EntryType::FileStream as u8);
-                for byte in entry.as_bytes() {
-                    tbs_data.push(*byte);
-                }
-                let tbs_message = Message {
-                    Type: MessageType::QueueEntryRequest,
-                    Priority: priority,
-                    Data: tbs_data,
-                };
-                self.send_message(tbs_message)?;
-                Ok("Pushed '".to_string() + &entry + "' as a filestream")
+                let tbs_message = self.entry_message(
+                    EntryType::FileStream,
+                    &entry,
+                    priority,
+                    schedule,
+                    None,
+                    None,
+                );
+                self.send_message(with_format(tbs_message, format))?;
+                Ok("Pushed '".to_string() + &entry + "' as a filestream" + &scheduled_suffix)
             }
             _ => {
                 return Err(io::Error::new(
@@ -281,3 +944,145 @@ impl SocketCom {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playlist_select_range_reverse_max() {
+        let options = PlaylistOptions {
+            items: Some((2, 5)),
+            max_count: Some(3),
+            reverse: true,
+            ..Default::default()
+        };
+        assert_eq!(options.select((1..=10).collect()), vec![5, 4, 3]);
+        let open_ended = PlaylistOptions {
+            items: Some(parse_playlist_items("9-").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(open_ended.select((1..=10).collect()), vec![9, 10]);
+    }
+
+    #[test]
+    fn test_grouped_entry_round_trip() {
+        let com = SocketCom::new().unwrap();
+        let format = FormatOptions {
+            max_height: Some(720),
+            codec: Some("avc1".to_string()),
+            audio_only: None,
+        };
+        let message = with_format(
+            com.entry_message(
+                EntryType::LocalMedia,
+                "/media/demo.mkv",
+                60,
+                Some(Schedule::NotBefore(1000)),
+                None,
+                Some(42),
+            ),
+            &format,
+        );
+        let mut raw = message.Priority.to_le_bytes().to_vec();
+        raw.push(message.Type as u8);
+        raw.extend_from_slice(&message.Data);
+        match decode_message(&raw).unwrap() {
+            Request::QueueEntry {
+                priority,
+                location,
+                schedule,
+                group,
+                format: decoded_format,
+                ..
+            } => {
+                assert_eq!(priority, 60);
+                assert_eq!(location, "/media/demo.mkv");
+                assert_eq!(schedule, Some(Schedule::NotBefore(1000)));
+                assert_eq!(group, Some(42));
+                assert_eq!(decoded_format, Some(format));
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn test_describe_out_of_range_schedule() {
+        assert_eq!(
+            Schedule::StartAt(i64::MAX).describe(),
+            format!("at {}", i64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_mute_control_round_trip() {
+        let com = SocketCom::new().unwrap();
+        let message = com.control_message(ControlCommand::SetMute, true as u64);
+        let mut raw = message.Priority.to_le_bytes().to_vec();
+        raw.push(message.Type as u8);
+        raw.extend_from_slice(&message.Data);
+        match decode_message(&raw).unwrap() {
+            Request::Control {
+                command: ControlCommand::SetMute,
+                argument,
+                ..
+            } => assert_eq!(argument, Some(1)),
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn test_format_selector() {
+        assert_eq!(FormatOptions::default().selector(), None);
+        let video = FormatOptions {
+            max_height: Some(1080),
+            codec: Some("vp9".to_string()),
+            audio_only: None,
+        };
+        assert_eq!(
+            video.selector().unwrap(),
+            "bestvideo[height<=1080][vcodec^=vp9]+bestaudio/bestvideo[height<=1080]+bestaudio/best[height<=1080]/best"
+        );
+        let audio = FormatOptions {
+            max_height: None,
+            codec: Some("opus".to_string()),
+            audio_only: Some(true),
+        }
+        .or(&video);
+        assert_eq!(
+            audio.selector().unwrap(),
+            "bestaudio[acodec^=opus]/bestaudio/best"
+        );
+        assert_eq!(audio.cache_suffix(), "-h1080-opus-audio");
+        // An entry can fetch the video when the daemon defaults to audio only
+        let defaults = FormatOptions {
+            audio_only: Some(true),
+            ..Default::default()
+        };
+        let entry = FormatOptions {
+            audio_only: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(entry.or(&defaults).selector(), None);
+        assert_eq!(entry.or(&defaults).cache_suffix(), "");
+        assert_eq!(
+            FormatOptions::from_bytes(&entry.to_bytes()),
+            Some((entry.clone(), 6))
+        );
+    }
+
+    #[test]
+    fn test_playlist_options_wire_format() {
+        let options = PlaylistOptions {
+            items: Some((3, 7)),
+            max_count: Some(2),
+            reverse: false,
+            shuffle: true,
+            lazy: true,
+        };
+        assert_eq!(
+            PlaylistOptions::from_bytes(&options.to_bytes()),
+            Some(options)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 // General
+use std::collections::HashMap;
 use std::io::Result;
 use std::string::String;
 
@@ -21,31 +22,83 @@ use std::sync::mpsc::{Receiver, Sender};
 // Logging
 use log::warn;
 
+use crate::socket_com::Schedule;
+
+pub const STATUS_FILE_PATH: &str = "/tmp/smqueue.status";
+pub const QUEUE_FILE_PATH: &str = "/tmp/smqueue.queue";
+
 #[derive(Debug, Clone)]
 pub struct QueueEntry {
     pub id: u64,
     pub priority: u64,
     pub entry_type: String,
-    pub file_location: String, // Can be local filepath or url
+    pub file_location: String,      // Can be local filepath or url
+    pub schedule: Option<Schedule>, // Set while the entry waits for its scheduled time
+    pub group: Option<u64>,         // Shared by entries queued together
 }
 
 impl QueueEntry {
-    pub fn new(id: u64, priority: u64, entry_type: String, file_location: String) -> Self {
+    pub fn new(
+        id: u64,
+        priority: u64,
+        entry_type: String,
+        file_location: String,
+        schedule: Option<Schedule>,
+        group: Option<u64>,
+    ) -> Self {
         let stru = QueueEntry {
             id: id,
             priority: priority,
             entry_type: entry_type,
             file_location: file_location,
+            schedule,
+            group,
         };
         return stru;
     }
 }
 
+// Splits "type#<group>@at:<unix time>" into the type, its group and its schedule
+fn parse_entry_type(field: &str) -> Result<(String, Option<u64>, Option<Schedule>)> {
+    let (field, schedule_field) = match field.split_once('@') {
+        Some((field, schedule_field)) => (field, Some(schedule_field)),
+        None => (field, None),
+    };
+    let (entry_type, group) = match field.split_once('#') {
+        Some((entry_type, group)) => match group.parse::<u64>() {
+            Ok(value) => (entry_type, Some(value)),
+            Err(error) => return Err(Error::new(ErrorKind::InvalidData, error)),
+        },
+        None => (field, None),
+    };
+    let schedule = match schedule_field.map(|value| value.split_once(':')) {
+        Some(Some((kind, timestamp))) => {
+            let timestamp = match timestamp.parse::<i64>() {
+                Ok(value) => value,
+                Err(error) => return Err(Error::new(ErrorKind::InvalidData, error)),
+            };
+            match kind {
+                "after" => Some(Schedule::NotBefore(timestamp)),
+                "at" => Some(Schedule::StartAt(timestamp)),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid schedule")),
+            }
+        }
+        Some(None) => return Err(Error::new(ErrorKind::InvalidData, "Invalid schedule")),
+        None => None,
+    };
+    Ok((entry_type.to_string(), group, schedule))
+}
+
 #[derive(Debug)]
 pub struct QueueInfo {
     pub playback_state: PlaybackState,
     pub playback_time: f64,
     pub entry_list: Vec<QueueEntry>,
+    pub repeat_mode: RepeatMode,
+    pub shuffle: bool,
+    pub downloads: HashMap<u64, String>, // Download progress by queue id
+    pub volume: u64,                     // mpv's volume in percent
+    pub muted: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +109,37 @@ pub enum PlaybackState {
     Stopped,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepeatMode {
+    Off = 0,
+    One,   // Replays the current entry
+    Queue, // Finished entries go back to the end of the queue
+}
+
+impl RepeatMode {
+    pub fn from_u64(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(RepeatMode::Off),
+            1 => Some(RepeatMode::One),
+            2 => Some(RepeatMode::Queue),
+            _ => None,
+        }
+    }
+    pub fn next(&self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Queue,
+            RepeatMode::Queue => RepeatMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 impl fmt::Display for PlaybackState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -70,6 +154,11 @@ impl QueueInfo {
             playback_state: PlaybackState::Stopped,
             playback_time: 0.0,
             entry_list: Vec::new(),
+            repeat_mode: RepeatMode::Off,
+            shuffle: false,
+            downloads: HashMap::new(),
+            volume: 100,
+            muted: false,
         };
         return stru;
     }
@@ -148,12 +237,16 @@ fn update_from_queue_file(
                 }
             };
 
+            let (entry_type, group, schedule) = parse_entry_type(types[2])?;
+
             // Append new queue entry after parsing
             entries.push(QueueEntry::new(
                 id,
                 priority,
-                types[2].to_string(),
+                entry_type,
                 types[3].to_string(),
+                schedule,
+                group,
             ));
         }
     }
@@ -216,9 +309,41 @@ fn update_from_status_file(
         }
     }
 
+    // Playback modes are optional, older daemons do not write them
+    let repeat_mode = match lines.next() {
+        Some("One") => RepeatMode::One,
+        Some("Queue") => RepeatMode::Queue,
+        _ => RepeatMode::Off,
+    };
+    let shuffle = lines.next() == Some("Shuffle");
+
+    // Remaining lines are "download;<id>;<progress>" for entries being prefetched
+    // and "volume;<percent>;<muted>" for mpv's volume
+    let mut downloads = HashMap::new();
+    let mut volume = None;
+    for line in lines {
+        let fields: Vec<&str> = line.splitn(3, ';').collect();
+        if fields.len() == 3 && fields[0] == "download" {
+            if let Ok(id) = fields[1].parse::<u64>() {
+                downloads.insert(id, fields[2].to_string());
+            }
+        } else if fields.len() == 3 && fields[0] == "volume" {
+            if let Ok(percent) = fields[1].parse::<u64>() {
+                volume = Some((percent, fields[2] == "1"));
+            }
+        }
+    }
+
     // Assign to status info
     status_info.lock().unwrap().playback_time = playback_time;
     status_info.lock().unwrap().playback_state = playback_state;
+    status_info.lock().unwrap().repeat_mode = repeat_mode;
+    status_info.lock().unwrap().shuffle = shuffle;
+    status_info.lock().unwrap().downloads = downloads;
+    if let Some((percent, muted)) = volume {
+        status_info.lock().unwrap().volume = percent;
+        status_info.lock().unwrap().muted = muted;
+    }
 
     return Ok(());
 }
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::io;
 use std::sync::mpsc::*;
 use std::time::Duration;
@@ -14,12 +15,13 @@ use tui::Terminal;
 
 use termion::input::TermRead;
 
-#[cfg(target_os = "linux")]
-use crate::alsa_controller::AlsaController;
 use crate::history_watcher::{HistoryWatcher, DEFAULT_HISTORY_ENTRIES_TO_FETCH};
-use crate::socket_com::{SocketCom, DEFAULT_PRIORITY};
-use crate::status_watcher::{PlaybackState, StatusWatcher};
+use crate::socket_com::{FormatOptions, PlaylistOptions, SocketCom, DEFAULT_PRIORITY};
+use crate::status_watcher::{
+    PlaybackState, QueueEntry, RepeatMode, StatusWatcher, QUEUE_FILE_PATH, STATUS_FILE_PATH,
+};
 use crate::tab_elements::TabsElements;
+use crate::volume_backend::{create_volume_backend, MpvVolume, VolumeBackend, VolumeConfig};
 
 use log::warn;
 
@@ -28,29 +30,80 @@ struct TuiState {
     playback_position_percent: f64, // #TODO: Should be Duration when backend supports actual playback
 }
 
+// A line in the queue table, entries sharing a group are shown as one collapsible row
+#[derive(Debug, Clone)]
+enum QueueRow {
+    Entry(QueueEntry),
+    Group {
+        group: u64,
+        expanded: bool,
+        entries: Vec<QueueEntry>,
+    },
+}
+
+// The entry currently playing is always shown on its own, other group members are
+// collected at the position of the first member of their group.
+fn queue_rows(queue_list: &[QueueEntry], expanded_groups: &HashSet<u64>) -> Vec<QueueRow> {
+    let mut rows = vec![];
+    let mut seen_groups = HashSet::new();
+    for (index, entry) in queue_list.iter().enumerate() {
+        let group = match entry.group {
+            Some(group) if index != 0 => group,
+            _ => {
+                rows.push(QueueRow::Entry(entry.clone()));
+                continue;
+            }
+        };
+        if !seen_groups.insert(group) {
+            continue;
+        }
+        let entries: Vec<QueueEntry> = queue_list[1..]
+            .iter()
+            .filter(|member| member.group == Some(group))
+            .cloned()
+            .collect();
+        if entries.len() == 1 {
+            rows.push(QueueRow::Entry(entry.clone()));
+            continue;
+        }
+        let expanded = expanded_groups.contains(&group);
+        rows.push(QueueRow::Group {
+            group,
+            expanded,
+            entries: entries.clone(),
+        });
+        if expanded {
+            rows.extend(entries.into_iter().map(QueueRow::Entry));
+        }
+    }
+    rows
+}
+
 pub struct TerminalUi {
     terminal:
         tui::Terminal<tui::backend::TermionBackend<termion::raw::RawTerminal<std::io::Stdout>>>,
     current_status: StatusWatcher,
     history_log: HistoryWatcher,
+    volume_config: VolumeConfig, // Volume control behind the volume bar
 }
 
 impl TerminalUi {
-    pub fn new(history_file_path: String) -> Result<Self, io::Error> {
+    pub fn new(history_file_path: String, volume_config: VolumeConfig) -> Result<Self, io::Error> {
         let stdout = io::stdout().into_raw_mode()?;
         let backend = TermionBackend::new(stdout);
         let terminal_backend = Terminal::new(backend)?;
         let mut tui_ui = TerminalUi {
             terminal: terminal_backend,
             current_status: StatusWatcher::new(
-                path::PathBuf::from("/tmp/smqueue.status"),
-                path::PathBuf::from("/tmp/smqueue.queue"),
+                path::PathBuf::from(STATUS_FILE_PATH),
+                path::PathBuf::from(QUEUE_FILE_PATH),
             )?,
             history_log: HistoryWatcher::new(
                 path::PathBuf::from(history_file_path),
                 DEFAULT_HISTORY_ENTRIES_TO_FETCH,
                 0,
             )?,
+            volume_config,
         };
         tui_ui.current_status.start();
         tui_ui.history_log.start();
@@ -73,8 +126,14 @@ impl TerminalUi {
             }
         });
         let mut tab_select = 0;
-        #[cfg(target_os = "linux")]
-        let mut alsa_controller = AlsaController::new()?;
+        // The gauge and +/- use the first volume, Alt with +/- the system volume
+        let mut volumes: Vec<Box<dyn VolumeBackend>> =
+            vec![create_volume_backend(&self.volume_config)?];
+        if self.volume_config.mpv_keys {
+            let mpv_volume = MpvVolume::new(self.current_status.status_info.clone())?;
+            volumes.insert(0, Box::new(mpv_volume));
+        }
+        let system = volumes.len() - 1;
         let mut socket_controller = SocketCom::new()?;
         let mut queue_tab_element = TabsElements::new("Queue 🔜")?;
         let mut history_tab_element = TabsElements::new("History 📜")?;
@@ -101,6 +160,16 @@ impl TerminalUi {
             .unwrap()
             .playback_state
             .clone();
+        let mut repeat_mode = self.current_status.status_info.lock().unwrap().repeat_mode;
+        let mut shuffle = self.current_status.status_info.lock().unwrap().shuffle;
+        let mut downloads = self
+            .current_status
+            .status_info
+            .lock()
+            .unwrap()
+            .downloads
+            .clone();
+        let mut expanded_groups: HashSet<u64> = HashSet::new();
         let mut update_screen = true;
 
         loop {
@@ -133,14 +202,32 @@ impl TerminalUi {
                             tab_select = tab_select - 1;
                         }
                     }
-                    #[cfg(target_os = "linux")]
                     termion::event::Key::Char('+') | termion::event::Key::Char('k') => {
-                        alsa_controller.volume_increment_db(1)?;
+                        volumes[0].volume_increment(1)?;
                     }
 
-                    #[cfg(target_os = "linux")]
                     termion::event::Key::Char('-') | termion::event::Key::Char('j') => {
-                        alsa_controller.volume_decrement_db(1)?;
+                        volumes[0].volume_decrement(1)?;
+                    }
+
+                    termion::event::Key::Char('m') => {
+                        let muted = volumes[0].toggle_mute()?;
+                        // Muting the system mutes mpv as well, mpv's volume mutes itself
+                        if system == 0 {
+                            socket_controller.set_mute(muted)?;
+                        }
+                    }
+
+                    termion::event::Key::Alt('+') | termion::event::Key::Alt('k') => {
+                        volumes[system].volume_increment(1)?;
+                    }
+
+                    termion::event::Key::Alt('-') | termion::event::Key::Alt('j') => {
+                        volumes[system].volume_decrement(1)?;
+                    }
+
+                    termion::event::Key::Alt('m') => {
+                        volumes[system].toggle_mute()?;
                     }
 
                     termion::event::Key::Down => match tab_select {
@@ -176,8 +263,15 @@ impl TerminalUi {
                                         .unwrap()
                                         .entry_list
                                         .clone();
-                                    let queue_elem = queue_list[pos].clone();
-                                    socket_controller.delete_entry(queue_elem.id)?;
+                                    match queue_rows(&queue_list, &expanded_groups).get(pos) {
+                                        Some(QueueRow::Entry(queue_elem)) => {
+                                            socket_controller.delete_entry(queue_elem.id)?
+                                        }
+                                        Some(QueueRow::Group { group, .. }) => {
+                                            socket_controller.remove_group(*group)?
+                                        }
+                                        None => {}
+                                    }
                                 }
                             }
                             _ => {}
@@ -200,6 +294,42 @@ impl TerminalUi {
                         // Tab
                         socket_controller.skip_playback()?;
                     }
+                    termion::event::Key::Char('u') => {
+                        socket_controller.undo()?;
+                    }
+                    termion::event::Key::Char('l') => {
+                        socket_controller.set_repeat_mode(repeat_mode.next())?;
+                    }
+                    termion::event::Key::Char('s') => {
+                        socket_controller.set_shuffle(!shuffle)?;
+                    }
+                    termion::event::Key::Char('e') => {
+                        if tab_select == 0 {
+                            let pos = queue_tab_element.table_list_pos;
+                            if let Some(QueueRow::Group { group, .. }) =
+                                queue_rows(&queue_list, &expanded_groups).get(pos)
+                            {
+                                if !expanded_groups.remove(group) {
+                                    expanded_groups.insert(*group);
+                                }
+                            }
+                        }
+                    }
+                    termion::event::Key::Char('<') | termion::event::Key::Char('>') => {
+                        if tab_select == 0 {
+                            let pos = queue_tab_element.table_list_pos;
+                            if let Some(QueueRow::Group { group, entries, .. }) =
+                                queue_rows(&queue_list, &expanded_groups).get(pos)
+                            {
+                                let priority = entries[0].priority;
+                                let priority = match event {
+                                    termion::event::Key::Char('>') => priority.saturating_add(10),
+                                    _ => priority.saturating_sub(10),
+                                };
+                                socket_controller.set_group_priority(*group, priority)?;
+                            }
+                        }
+                    }
                     termion::event::Key::Char('\n') => {
                         match tab_select {
                             0 => {
@@ -212,8 +342,15 @@ impl TerminalUi {
                                         .unwrap()
                                         .entry_list
                                         .clone();
-                                    let queue_elem = queue_list[pos].clone();
-                                    socket_controller.promote_entry(queue_elem.id)?;
+                                    match queue_rows(&queue_list, &expanded_groups).get(pos) {
+                                        Some(QueueRow::Entry(queue_elem)) => {
+                                            socket_controller.promote_entry(queue_elem.id)?
+                                        }
+                                        Some(QueueRow::Group { group, .. }) => {
+                                            socket_controller.promote_group(*group)?
+                                        }
+                                        None => {}
+                                    }
                                 }
                             }
                             1 => {
@@ -226,6 +363,9 @@ impl TerminalUi {
                                         history_element.location.clone(),
                                         DEFAULT_PRIORITY,
                                         false,
+                                        None,
+                                        &PlaylistOptions::default(),
+                                        &FormatOptions::default(),
                                     ) {
                                         Ok(_) => {}
                                         Err(value) => warn!(
@@ -247,8 +387,10 @@ impl TerminalUi {
             {
                 update_screen = true;
             }
-            #[cfg(target_os = "linux")]
-            if alsa_controller.wait_for_volume_event() {
+            if volumes
+                .iter_mut()
+                .any(|volume| volume.wait_for_volume_event())
+            {
                 update_screen = true;
             }
 
@@ -274,13 +416,26 @@ impl TerminalUi {
                     .unwrap()
                     .playback_state
                     .clone();
+                repeat_mode = self.current_status.status_info.lock().unwrap().repeat_mode;
+                shuffle = self.current_status.status_info.lock().unwrap().shuffle;
+                downloads = self
+                    .current_status
+                    .status_info
+                    .lock()
+                    .unwrap()
+                    .downloads
+                    .clone();
 
-                #[cfg(target_os = "linux")]
-                alsa_controller.update_volume();
+                for volume in volumes.iter_mut() {
+                    if let Err(error) = volume.update_volume() {
+                        log::debug!("Could not read the {} volume: {}", volume.name(), error);
+                    }
+                }
 
+                let queue_rows = queue_rows(&queue_list, &expanded_groups);
                 let mut queue_size = 0;
-                if queue_list.len() > 0 {
-                    queue_size = queue_list.len() - 1;
+                if !queue_rows.is_empty() {
+                    queue_size = queue_rows.len() - 1;
                 }
                 queue_tab_element.update_size(queue_size);
                 let history_entries = self.history_log.get_history();
@@ -307,11 +462,20 @@ impl TerminalUi {
                         )
                         .split(f.size());
 
+                    let mut playback_title = playback_state.to_string();
+                    match repeat_mode {
+                        RepeatMode::Off => {}
+                        RepeatMode::One => playback_title += " 🔂 Repeat one",
+                        RepeatMode::Queue => playback_title += " 🔁 Repeat queue",
+                    }
+                    if shuffle {
+                        playback_title += " 🔀 Shuffle";
+                    }
                     let playback_gauge = LineGauge::default()
                         .block(
                             Block::default()
                                 .borders(Borders::BOTTOM)
-                                .title(playback_state.to_string()),
+                                .title(playback_title),
                         )
                         .gauge_style(
                             Style::default()
@@ -323,20 +487,22 @@ impl TerminalUi {
                         .ratio((playback_percentage as f64) / 100.0);
                     f.render_widget(playback_gauge, chunks[0]);
 
-                    #[cfg(target_os = "linux")]
+                    let volume = &mut volumes[0];
+                    let muted = volume.is_muted();
                     let volume_gauge = LineGauge::default()
-                        .block(Block::default().borders(Borders::NONE).title(
-                            "Volume 🔊 ".to_string() + &alsa_controller.get_description_str(),
-                        ))
+                        .block(Block::default().borders(Borders::NONE).title(if muted {
+                            "Volume 🔇 Muted ".to_string() + &volume.description()
+                        } else {
+                            "Volume 🔊 ".to_string() + &volume.description()
+                        }))
                         .gauge_style(
                             Style::default()
-                                .fg(Color::White)
+                                .fg(if muted { Color::DarkGray } else { Color::White })
                                 .bg(Color::Black)
                                 .add_modifier(Modifier::BOLD),
                         )
                         .line_set(symbols::line::ROUNDED)
-                        .ratio(alsa_controller.get_human_ear_volume_normalized());
-                    #[cfg(target_os = "linux")]
+                        .ratio(volume.normalized_volume());
                     f.render_widget(volume_gauge, chunks[1]);
 
                     let paragraph =
@@ -367,16 +533,50 @@ impl TerminalUi {
                     let mut rows = vec![];
                     let mut first = true;
 
-                    for line in queue_list {
+                    for row in queue_rows {
                         let mut style = Style::default().fg(Color::Gray);
                         if first {
                             style = Style::default().fg(Color::Yellow);
                             first = false;
                         }
+                        let line = match row {
+                            QueueRow::Entry(line) => line,
+                            QueueRow::Group {
+                                expanded, entries, ..
+                            } => {
+                                let marker = if expanded { "▼" } else { "▶" };
+                                rows.push(
+                                    Row::new(vec![
+                                        entries[0].priority.to_string(),
+                                        format!("{} Group", marker),
+                                        "".to_string(),
+                                        format!(
+                                            "{} entries: {}",
+                                            entries.len(),
+                                            entries[0].file_location
+                                        ),
+                                    ])
+                                    .style(Style::default().fg(Color::Cyan)),
+                                );
+                                continue;
+                            }
+                        };
+                        // Scheduled entries show their time, prefetched ones their download
+                        let scheduled = match line.schedule {
+                            Some(value) => {
+                                style = Style::default().fg(Color::DarkGray);
+                                value.describe()
+                            }
+                            None => downloads.get(&line.id).cloned().unwrap_or_default(),
+                        };
+                        if scheduled.starts_with("Failed") {
+                            style = Style::default().fg(Color::Red);
+                        }
                         rows.push(
                             Row::new(vec![
                                 line.priority.to_string(),
                                 line.entry_type,
+                                scheduled,
                                 line.file_location,
                             ])
                             .style(style),
@@ -388,7 +588,7 @@ impl TerminalUi {
                         .style(Style::default().fg(Color::White))
                         // It has an optional header, which is simply a Row always visible at the top.
                         .header(
-                            Row::new(vec!["Pri", "Type", "Location"])
+                            Row::new(vec!["Pri", "Type", "Status", "Location"])
                                 .style(Style::default().fg(Color::Yellow))
                                 // If you want some space between the header and the rest of the rows, you can always
                                 // specify some margin at the bottom.
@@ -404,7 +604,8 @@ impl TerminalUi {
                         .widths(&[
                             Constraint::Percentage(3),
                             Constraint::Percentage(13),
-                            Constraint::Percentage(84),
+                            Constraint::Percentage(22),
+                            Constraint::Percentage(62),
                         ])
                         // ...and they can be separated by a fixed spacing.
                         .column_spacing(1)
@@ -453,7 +654,15 @@ impl TerminalUi {
                             Style::default().fg(Color::Gray),
                         )),
                         Spans::from(Span::styled(
-                            "+/-: Adjusts volume on the system",
+                            "+/-: Adjusts volume on the system, or of mpv with --volume-keys mpv",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "m: Mute or unmute the system and mpv, or only mpv with --volume-keys mpv",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "Alt and +/-/m: Adjusts or mutes the system volume",
                             Style::default().fg(Color::Gray),
                         )),
                         Spans::from(Span::styled(
@@ -473,7 +682,7 @@ impl TerminalUi {
                             Style::default().fg(Color::Gray),
                         )),
                         Spans::from(Span::styled(
-                            "Delete or r: Removes entry from queue",
+                            "Delete or r: Removes entry or group from queue",
                             Style::default().fg(Color::Gray),
                         )),
                         Spans::from(Span::styled(
@@ -484,6 +693,26 @@ impl TerminalUi {
                             "Ctrl-r: Clears entire queue",
                             Style::default().fg(Color::Gray),
                         )),
+                        Spans::from(Span::styled(
+                            "u: Undo the last remove, clear or jump in the queue",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "l: Cycle repeat mode (off, one, queue)",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "s: Toggle shuffle within the same priority",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "e: Expand or collapse a playlist or directory group",
+                            Style::default().fg(Color::Gray),
+                        )),
+                        Spans::from(Span::styled(
+                            "</>: Lower or raise the priority of a group",
+                            Style::default().fg(Color::Gray),
+                        )),
                         Spans::from(Span::styled(
                             "Enter: (Queue)Jump to or add to entry (History)",
                             Style::default().fg(Color::Gray),
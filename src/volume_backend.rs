@@ -0,0 +1,465 @@
+#[cfg(target_os = "linux")]
+use crate::alsa_controller::{AlsaConfig, AlsaController};
+use crate::socket_com::SocketCom;
+use crate::status_watcher::QueueInfo;
+use log::{debug, warn};
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+
+/// A volume control the terminal UI shows and changes
+pub trait VolumeBackend {
+    fn name(&self) -> String;
+    /// True if the volume changed outside of us since the last call
+    fn wait_for_volume_event(&mut self) -> bool;
+    fn update_volume(&mut self) -> Result<(), io::Error>;
+    /// Raises the volume by num_steps percent
+    fn volume_increment(&mut self, num_steps: u32) -> Result<(), io::Error>;
+    fn volume_decrement(&mut self, num_steps: u32) -> Result<(), io::Error>;
+    /// Mutes or unmutes, returns true if it is muted now
+    fn toggle_mute(&mut self) -> Result<bool, io::Error>;
+    fn is_muted(&self) -> bool;
+    fn description(&self) -> String;
+    /// Volume from 0 to 1 the way it is heard, for the gauge
+    fn normalized_volume(&mut self) -> f64;
+}
+
+#[cfg(target_os = "linux")]
+impl VolumeBackend for AlsaController {
+    fn name(&self) -> String {
+        "alsa".to_string()
+    }
+
+    fn wait_for_volume_event(&mut self) -> bool {
+        AlsaController::wait_for_volume_event(self)
+    }
+
+    fn update_volume(&mut self) -> Result<(), io::Error> {
+        AlsaController::update_volume(self)
+    }
+
+    fn volume_increment(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        self.volume_increment_db(num_steps)
+    }
+
+    fn volume_decrement(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        self.volume_decrement_db(num_steps)
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, io::Error> {
+        AlsaController::toggle_mute(self)
+    }
+
+    fn is_muted(&self) -> bool {
+        AlsaController::is_muted(self)
+    }
+
+    fn description(&self) -> String {
+        self.get_description_str()
+    }
+
+    fn normalized_volume(&mut self) -> f64 {
+        self.get_human_ear_volume_normalized()
+    }
+}
+
+/// The sink pactl changes unless another one is configured
+pub const DEFAULT_PULSE_SINK: &str = "@DEFAULT_SINK@";
+
+/// Volume of a PulseAudio or PipeWire sink, changed through pactl
+pub struct PulseBackend {
+    sink: String,
+    percent: u32,
+    db: Option<f64>,
+    muted: bool,
+    events: Receiver<()>,
+    subscriber: Option<Child>, // pactl subscribe, sends the change events
+}
+
+// Runs pactl and returns what it printed
+fn pactl(args: &[&str]) -> Result<String, io::Error> {
+    let output = Command::new("pactl")
+        .args(args)
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "pactl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Parses the first channel of `pactl get-sink-volume` into its percent and dB
+pub fn parse_pactl_volume(output: &str) -> Option<(u32, Option<f64>)> {
+    // Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB
+    let channel = output.split(',').next()?;
+    let mut fields = channel.split('/').skip(1).map(|field| field.trim());
+    let percent = fields.next()?.strip_suffix('%')?.trim().parse().ok()?;
+    let db = fields
+        .next()
+        .and_then(|field| field.strip_suffix("dB"))
+        .and_then(|field| field.trim().parse().ok());
+    Some((percent, db))
+}
+
+/// Parses `pactl get-sink-mute`, e.g. "Mute: yes"
+pub fn parse_pactl_mute(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("Mute:")?.trim() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+impl PulseBackend {
+    pub fn new(sink: &str) -> Result<Self, io::Error> {
+        let (events_tx, events) = channel();
+        let subscriber = match Command::new("pactl")
+            .arg("subscribe")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(mut child) => {
+                let stdout = child.stdout.take().unwrap();
+                std::thread::spawn(move || {
+                    for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                        // Event 'change' on sink #57
+                        if line.contains("on sink") && events_tx.send(()).is_err() {
+                            return;
+                        }
+                    }
+                });
+                Some(child)
+            }
+            Err(error) => {
+                warn!("Could not subscribe to pactl events: {}", error);
+                None
+            }
+        };
+        let mut backend = PulseBackend {
+            sink: sink.to_string(),
+            percent: 0,
+            db: None,
+            muted: false,
+            events,
+            subscriber,
+        };
+        backend.update_volume()?;
+        Ok(backend)
+    }
+
+    fn set_volume(&mut self, volume: &str) -> Result<(), io::Error> {
+        debug!("Setting volume of {} to {}", self.sink, volume);
+        pactl(&["set-sink-volume", &self.sink, volume])?;
+        self.update_volume()
+    }
+}
+
+impl Drop for PulseBackend {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.subscriber {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl VolumeBackend for PulseBackend {
+    fn name(&self) -> String {
+        "pulse".to_string()
+    }
+
+    fn wait_for_volume_event(&mut self) -> bool {
+        let mut changed = false;
+        while self.events.try_recv().is_ok() {
+            changed = true;
+        }
+        changed
+    }
+
+    fn update_volume(&mut self) -> Result<(), io::Error> {
+        let invalid = |output: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected pactl output {:?}", output),
+            )
+        };
+        let output = pactl(&["get-sink-volume", &self.sink])?;
+        let (percent, db) = parse_pactl_volume(&output).ok_or_else(|| invalid(&output))?;
+        let output = pactl(&["get-sink-mute", &self.sink])?;
+        self.muted = parse_pactl_mute(&output).ok_or_else(|| invalid(&output))?;
+        self.percent = percent;
+        self.db = db;
+        Ok(())
+    }
+
+    fn volume_increment(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        // pactl goes past 100%, the gauge and ALSA do not
+        let target = (self.percent + num_steps).min(100);
+        if target > self.percent {
+            self.set_volume(&format!("{}%", target))?;
+        }
+        Ok(())
+    }
+
+    fn volume_decrement(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        self.set_volume(&format!("{}%", self.percent.saturating_sub(num_steps)))
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, io::Error> {
+        pactl(&["set-sink-mute", &self.sink, "toggle"])?;
+        self.update_volume()?;
+        Ok(self.muted)
+    }
+
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    fn description(&self) -> String {
+        match self.db {
+            Some(db) => format!("{}% / {}dB", self.percent, db),
+            None => format!("{}%", self.percent),
+        }
+    }
+
+    fn normalized_volume(&mut self) -> f64 {
+        (f64::from(self.percent) / 100.0).min(1.0)
+    }
+}
+
+/// Used when there is no volume control, the gauge stays empty
+pub struct NullBackend;
+
+impl VolumeBackend for NullBackend {
+    fn name(&self) -> String {
+        "none".to_string()
+    }
+
+    fn wait_for_volume_event(&mut self) -> bool {
+        false
+    }
+
+    fn update_volume(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn volume_increment(&mut self, _num_steps: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn volume_decrement(&mut self, _num_steps: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+
+    fn is_muted(&self) -> bool {
+        false
+    }
+
+    fn description(&self) -> String {
+        "No volume control".to_string()
+    }
+
+    fn normalized_volume(&mut self) -> f64 {
+        0.0
+    }
+}
+
+/// Volume of the daemon's mpv, changed through the socket and read from the status file
+pub struct MpvVolume {
+    socket: SocketCom,
+    status_info: Arc<Mutex<QueueInfo>>,
+    volume: u64,
+    muted: bool,
+    pending: Option<(u64, bool)>, // Sent to the daemon but not in the status file yet
+}
+
+impl MpvVolume {
+    pub fn new(status_info: Arc<Mutex<QueueInfo>>) -> Result<Self, io::Error> {
+        let mut mpv_volume = MpvVolume {
+            socket: SocketCom::new()?,
+            status_info,
+            volume: 0,
+            muted: false,
+            pending: None,
+        };
+        mpv_volume.update_volume()?;
+        Ok(mpv_volume)
+    }
+
+    // The volume and mute state the daemon last reported
+    fn reported(&self) -> (u64, bool) {
+        let status_info = self.status_info.lock().unwrap();
+        (status_info.volume, status_info.muted)
+    }
+
+    fn request(&mut self, volume: u64, muted: bool) -> Result<(), io::Error> {
+        if (volume, muted) == (self.volume, self.muted) {
+            return Ok(());
+        }
+        if volume != self.volume {
+            self.socket.set_volume(volume)?;
+        }
+        if muted != self.muted {
+            self.socket.set_mute(muted)?;
+        }
+        self.volume = volume;
+        self.muted = muted;
+        self.pending = Some((volume, muted));
+        Ok(())
+    }
+}
+
+impl VolumeBackend for MpvVolume {
+    fn name(&self) -> String {
+        "mpv".to_string()
+    }
+
+    fn wait_for_volume_event(&mut self) -> bool {
+        self.pending.is_none() && self.reported() != (self.volume, self.muted)
+    }
+
+    fn update_volume(&mut self) -> Result<(), io::Error> {
+        let reported = self.reported();
+        // Keeps what was asked for until the daemon reports it, quick key presses build on it
+        match self.pending {
+            Some(pending) if pending != reported => return Ok(()),
+            _ => self.pending = None,
+        }
+        self.volume = reported.0;
+        self.muted = reported.1;
+        Ok(())
+    }
+
+    fn volume_increment(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        let volume = (self.volume + u64::from(num_steps)).min(100);
+        self.request(volume, self.muted)
+    }
+
+    fn volume_decrement(&mut self, num_steps: u32) -> Result<(), io::Error> {
+        let volume = self.volume.saturating_sub(u64::from(num_steps));
+        self.request(volume, self.muted)
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, io::Error> {
+        self.request(self.volume, !self.muted)?;
+        Ok(self.muted)
+    }
+
+    fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    fn description(&self) -> String {
+        format!("mpv {}%", self.volume)
+    }
+
+    fn normalized_volume(&mut self) -> f64 {
+        (self.volume as f64 / 100.0).min(1.0)
+    }
+}
+
+/// Backends that can be picked by name, auto prefers pulse, then alsa
+pub const VOLUME_BACKEND_NAMES: [&str; 4] = ["auto", "alsa", "pulse", "none"];
+
+/// Which volume backend the terminal UI uses and its settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeConfig {
+    pub backend: String, // One of VOLUME_BACKEND_NAMES
+    #[cfg(target_os = "linux")]
+    pub alsa: AlsaConfig,
+    pub pulse_sink: String,
+    pub mpv_keys: bool, // +/- change mpv's volume, with Alt they change the system volume
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        VolumeConfig {
+            backend: "auto".to_string(),
+            #[cfg(target_os = "linux")]
+            alsa: AlsaConfig::default(),
+            pulse_sink: DEFAULT_PULSE_SINK.to_string(),
+            mpv_keys: false,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn alsa_backend(config: &VolumeConfig) -> Result<Box<dyn VolumeBackend>, io::Error> {
+    Ok(Box::new(AlsaController::new(config.alsa.clone())?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn alsa_backend(_config: &VolumeConfig) -> Result<Box<dyn VolumeBackend>, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Alsa is only supported on Linux",
+    ))
+}
+
+/// Creates the configured backend. Auto falls back to the null backend if nothing works.
+pub fn create_volume_backend(config: &VolumeConfig) -> Result<Box<dyn VolumeBackend>, io::Error> {
+    match config.backend.as_str() {
+        "auto" => {
+            let backend = PulseBackend::new(&config.pulse_sink)
+                .map(|backend| Box::new(backend) as Box<dyn VolumeBackend>)
+                .or_else(|error| {
+                    debug!("No pulse volume: {}", error);
+                    alsa_backend(config)
+                });
+            Ok(backend.unwrap_or_else(|error| {
+                warn!("No volume control found: {}", error);
+                Box::new(NullBackend)
+            }))
+        }
+        "alsa" => alsa_backend(config),
+        "pulse" => Ok(Box::new(PulseBackend::new(&config.pulse_sink)?)),
+        "none" => Ok(Box::new(NullBackend)),
+        name => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Unknown volume backend {}, expected one of {}",
+                name,
+                VOLUME_BACKEND_NAMES.join(", ")
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pactl_output() {
+        let output = "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB\n        balance 0.00\n";
+        assert_eq!(parse_pactl_volume(output), Some((50, Some(-18.06))));
+        // PipeWire sinks without a dB scale
+        assert_eq!(
+            parse_pactl_volume("Volume: mono: 65536 / 100%"),
+            Some((100, None))
+        );
+        assert_eq!(parse_pactl_volume("No such entity"), None);
+        assert_eq!(parse_pactl_mute("Mute: yes\n"), Some(true));
+        assert_eq!(parse_pactl_mute("Mute: no"), Some(false));
+        assert_eq!(parse_pactl_mute("Mute: maybe"), None);
+        assert!(create_volume_backend(&VolumeConfig {
+            backend: "oss".to_string(),
+            ..VolumeConfig::default()
+        })
+        .is_err());
+    }
+}